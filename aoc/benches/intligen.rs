@@ -0,0 +1,68 @@
+use aoc::intligen::{
+    binary, bytecode::Bytecode, load_machine, opcode::Registry, Instruction, Machine, Operation,
+    Program,
+};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+fn day_08(c: &mut Criterion) {
+    let input = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/../input/08"))
+        .expect("input/08");
+    let program = load_machine(&input).expect("program").program().to_vec();
+    let mut group = c.benchmark_group("input/08");
+    group.bench_function("machine", |b| {
+        b.iter_batched(
+            || program.clone(),
+            |program| Machine::new(black_box(program)).run_to_first(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("bytecode", |b| {
+        b.iter(|| Bytecode::decode(black_box(&program)).map(|bytecode| bytecode.run()))
+    });
+    group.finish();
+}
+
+/// A million instructions that all run once before jumping back to the start.
+fn million() -> Vec<Instruction> {
+    let len = 1_000_000;
+    let mut program: Vec<Instruction> = (0..len - 1)
+        .map(|index| match index % 3 {
+            0 => Instruction(Operation::Acc, 1),
+            1 => Instruction(Operation::Nop, -5),
+            _ => Instruction(Operation::Jmp, 1),
+        })
+        .collect();
+    program.push(Instruction(Operation::Jmp, 1 - len as isize));
+    program
+}
+
+fn synthetic(c: &mut Criterion) {
+    let program = million();
+    let mut group = c.benchmark_group("million");
+    group.sample_size(10);
+    group.bench_function("machine", |b| {
+        b.iter_batched(
+            || program.clone(),
+            |program| Machine::new(black_box(program)).run_to_first(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("bytecode", |b| {
+        b.iter(|| Bytecode::decode(black_box(&program)).map(|bytecode| bytecode.run()))
+    });
+    group.finish();
+}
+
+fn load(c: &mut Criterion) {
+    let program = million();
+    let text = Program(program.clone()).to_string();
+    let bytes = binary::encode(&Registry::default(), &program).expect("encode");
+    let mut group = c.benchmark_group("load");
+    group.sample_size(10);
+    group.bench_function("text", |b| b.iter(|| load_machine(black_box(&text))));
+    group.bench_function("binary", |b| b.iter(|| load_machine(black_box(&bytes))));
+    group.finish();
+}
+
+criterion_group!(benches, day_08, synthetic, load);
+criterion_main!(benches);
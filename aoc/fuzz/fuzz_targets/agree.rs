@@ -0,0 +1,13 @@
+#![no_main]
+use aoc::intligen::generate::{agree, Generator};
+use libfuzzer_sys::fuzz_target;
+use rand::{rngs::StdRng, SeedableRng};
+
+fuzz_target!(|seed: u64| {
+    let program = Generator::default()
+        .with_length(0..500)
+        .generate(&mut StdRng::seed_from_u64(seed));
+    if let Err(disagreement) = agree(&program) {
+        panic!("{:?}", disagreement);
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+use aoc::intligen::{binary, opcode::Registry};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut bytes = binary::MAGIC.to_vec();
+    bytes.extend_from_slice(data);
+    let registry = Registry::default();
+    if let Ok(program) = binary::decode(&registry, &bytes) {
+        let encoded = binary::encode(&registry, &program).expect("decoded programs encode");
+        assert_eq!(binary::decode(&registry, &encoded).ok(), Some(program));
+    }
+});
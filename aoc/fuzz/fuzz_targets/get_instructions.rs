@@ -0,0 +1,13 @@
+#![no_main]
+use aoc::intligen::{load_machine_with, opcode, Program};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    let _ = text.parse::<Program>();
+    let mut registry = opcode::Registry::default();
+    registry
+        .register(opcode::Mul)
+        .register(opcode::Jz)
+        .register(opcode::Hlt);
+    let _ = load_machine_with(&registry, text);
+});
@@ -1,85 +1,170 @@
-use std::collections::{HashMap, HashSet};
-fn shiny_count_rec(bag: &str, truth: &HashMap<String, HashMap<String, usize>>) -> usize {
-    truth
-        .get(bag)
-        .map(|hm| {
-            println!("{}", bag);
-            let result = 0;
-            if let Some(entries) = truth.get(bag) {
-                //  entries.iter().map(|(e, u)| 2)
-            }
-            hm.iter()
-                .fold(0, |v, (b, c)| v + *c * shiny_count_rec(b, truth))
-        })
-        .unwrap_or(1)
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use tracing::error;
+
+const SHINY_GOLD: &str = "shiny gold";
+
+/// A bag colour, interned by `BagGraph`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BagId(usize);
+
+/// The rules of which bags hold which, parsed once. Every colour named in
+/// the rules gets a `BagId`, in the order it first appears.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BagGraph {
+    names: Vec<String>,
+    ids: HashMap<String, BagId>,
+    children: Vec<Vec<(BagId, usize)>>,
+    parents: Vec<Vec<(BagId, usize)>>,
 }
-pub fn shiny_count(input: &str) -> usize {
-    match parse::get_bags(input) {
-        Ok((remainder, entries)) => {
-            if remainder.is_empty() {
-                let bags: HashMap<String, HashMap<String, usize>> =
-                    entries.iter().cloned().collect();
-
-                let bag = format!("shiny gold");
-
-                shiny_count_rec(&bag, &bags)
-            } else {
-                error!("{}", remainder);
-                0
-            }
+
+impl FromStr for BagGraph {
+    type Err = String;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (remainder, rules) = parse::get_bags(input).map_err(|err| err.to_string())?;
+        if !remainder.trim().is_empty() {
+            return Err(format!("unexpected input: {}", remainder));
         }
-        Err(err) => {
-            error!("{}", err);
-            0
+        let mut graph = BagGraph::default();
+        for (outer, contents) in rules {
+            let outer = graph.intern(outer);
+            for (inner, count) in contents {
+                let inner = graph.intern(inner);
+                graph.children[outer.0].push((inner, count));
+                graph.parents[inner.0].push((outer, count));
+            }
         }
+        Ok(graph)
     }
 }
 
-fn contains_shiny_gold_rec(
-    bag: &str,
-    known: &HashSet<String>,
-    truth: &HashMap<String, HashMap<String, usize>>,
-) -> bool {
-    known.contains(bag)
-        || truth
-            .get(bag)
-            .cloned()
-            .map(|hm| {
-                hm.iter()
-                    .any(|(b, _)| contains_shiny_gold_rec(b, known, truth))
-            })
-            .unwrap_or(false)
-}
-
-use tracing::error;
-pub fn shiny_gold(input: &str) -> usize {
-    match parse::get_bags(input) {
-        Ok((remainder, entries)) => {
-            if remainder.is_empty() {
-                let bags: HashMap<String, HashMap<String, usize>> =
-                    entries.iter().cloned().collect();
-                let mut contains_shiny_gold: HashSet<String> = HashSet::default();
-                contains_shiny_gold.insert(format!("shiny gold"));
-                for bag in entries.iter().map(|(bag, _content)| bag.clone()) {
-                    if contains_shiny_gold_rec(&bag, &contains_shiny_gold, &bags) {
-                        contains_shiny_gold.insert(bag);
-                    }
+impl BagGraph {
+    fn intern(&mut self, name: String) -> BagId {
+        if let Some(id) = self.ids.get(&name) {
+            return *id;
+        }
+        let id = BagId(self.names.len());
+        self.ids.insert(name.clone(), id);
+        self.names.push(name);
+        self.children.push(vec![]);
+        self.parents.push(vec![]);
+        id
+    }
+    pub fn id(&self, name: &str) -> Option<BagId> {
+        self.ids.get(name).copied()
+    }
+    pub fn name(&self, bag: BagId) -> &str {
+        &self.names[bag.0]
+    }
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+    /// The bags `bag` holds directly, with how many of each.
+    pub fn children(&self, bag: BagId) -> &[(BagId, usize)] {
+        &self.children[bag.0]
+    }
+    /// The bags that hold `bag` directly, with how many of it.
+    pub fn parents(&self, bag: BagId) -> &[(BagId, usize)] {
+        &self.parents[bag.0]
+    }
+    /// Every bag that ends up holding `bag`.
+    pub fn ancestors(&self, bag: BagId) -> HashSet<BagId> {
+        self.reach(bag, &self.parents)
+    }
+    /// Every bag that `bag` ends up holding.
+    pub fn descendants(&self, bag: BagId) -> HashSet<BagId> {
+        self.reach(bag, &self.children)
+    }
+    pub fn contains(&self, outer: BagId, inner: BagId) -> bool {
+        let mut seen = HashSet::new();
+        let mut pending = vec![outer];
+        while let Some(bag) = pending.pop() {
+            for (child, _) in &self.children[bag.0] {
+                if *child == inner {
+                    return true;
+                }
+                if seen.insert(*child) {
+                    pending.push(*child);
                 }
-
-                contains_shiny_gold.len() - 1
-            } else {
-                error!("{}", remainder);
-                0
             }
         }
+        false
+    }
+    /// How many bags are inside `bag`, all the way down. `None` when a bag
+    /// ends up holding itself, or the count does not fit.
+    pub fn count_inside(&self, bag: BagId) -> Option<usize> {
+        self.count_inside_memo(bag, &mut HashMap::new())
+    }
+    /// `memo` holds `None` for the bags still being counted.
+    fn count_inside_memo(
+        &self,
+        bag: BagId,
+        memo: &mut HashMap<BagId, Option<usize>>,
+    ) -> Option<usize> {
+        if let Some(known) = memo.get(&bag) {
+            return *known;
+        }
+        memo.insert(bag, None);
+        let mut total: usize = 0;
+        for (child, count) in &self.children[bag.0] {
+            let each = self.count_inside_memo(*child, memo)?.checked_add(1)?;
+            total = total.checked_add(count.checked_mul(each)?)?;
+        }
+        memo.insert(bag, Some(total));
+        Some(total)
+    }
+    fn reach(&self, bag: BagId, edges: &[Vec<(BagId, usize)>]) -> HashSet<BagId> {
+        let mut found = HashSet::new();
+        let mut pending = vec![bag];
+        while let Some(bag) = pending.pop() {
+            for (next, _) in &edges[bag.0] {
+                if found.insert(*next) {
+                    pending.push(*next);
+                }
+            }
+        }
+        found
+    }
+}
+
+/// Parses `input` and answers `query` about the shiny gold bag, 0 when
+/// there is none.
+fn about_shiny_gold(input: &str, query: impl Fn(&BagGraph, BagId) -> usize) -> usize {
+    match input.parse::<BagGraph>() {
+        Ok(graph) => graph
+            .id(SHINY_GOLD)
+            .map_or(0, |shiny_gold| query(&graph, shiny_gold)),
         Err(err) => {
             error!("{}", err);
             0
         }
     }
 }
+
+/// How many bags are inside a shiny gold bag.
+pub fn shiny_count(input: &str) -> usize {
+    about_shiny_gold(input, |graph, shiny_gold| {
+        graph.count_inside(shiny_gold).unwrap_or_else(|| {
+            error!("The shiny gold bag holds itself");
+            0
+        })
+    })
+}
+
+/// How many colours of bag end up holding a shiny gold bag.
+pub fn shiny_gold(input: &str) -> usize {
+    about_shiny_gold(input, |graph, shiny_gold| graph.ancestors(shiny_gold).len())
+}
 #[cfg(test)]
 mod tests {
+    use super::{BagGraph, BagId};
+    use std::collections::HashSet;
 
     const RULES: &str = "light red bags contain 1 bright white bag, 2 muted yellow bags.\ndark orange bags contain 3 bright white bags, 4 muted yellow bags.\nbright white bags contain 1 shiny gold bag.\nmuted yellow bags contain 2 shiny gold bags, 9 faded blue bags.\nshiny gold bags contain 1 dark olive bag, 2 vibrant plum bags.\ndark olive bags contain 3 faded blue bags, 4 dotted black bags.\nvibrant plum bags contain 5 faded blue bags, 6 dotted black bags.\nfaded blue bags contain no other bags.\ndotted black bags contain no other bags.";
     #[test]
@@ -96,10 +181,57 @@ mod tests {
     fn shiny() {
         assert_eq!(super::shiny_count(SHINY), 126)
     }
+
+    #[test]
+    fn graph() {
+        let graph: BagGraph = RULES.parse().expect("rules");
+        assert_eq!(graph.len(), 9);
+        let id = |name| graph.id(name).expect("bag");
+        let names = |bags: &[(BagId, usize)]| {
+            bags.iter()
+                .map(|(bag, count)| (graph.name(*bag), *count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(graph.children(id("muted yellow"))),
+            vec![("shiny gold", 2), ("faded blue", 9)]
+        );
+        assert_eq!(
+            names(graph.parents(id("shiny gold"))),
+            vec![("bright white", 1), ("muted yellow", 2)]
+        );
+        assert!(graph.children(id("faded blue")).is_empty());
+        let ancestors: HashSet<BagId> =
+            ["bright white", "muted yellow", "light red", "dark orange"]
+                .iter()
+                .map(|name| id(name))
+                .collect();
+        assert_eq!(graph.ancestors(id("shiny gold")), ancestors);
+        assert_eq!(graph.descendants(id("shiny gold")).len(), 4);
+        assert!(graph.contains(id("light red"), id("dotted black")));
+        assert!(!graph.contains(id("shiny gold"), id("light red")));
+        assert!(!graph.contains(id("faded blue"), id("faded blue")));
+        assert_eq!(graph.count_inside(id("faded blue")), Some(0));
+        assert_eq!(graph.id("plaid purple"), None);
+    }
+
+    #[test]
+    fn loops_and_errors() {
+        let graph: BagGraph =
+            "dim red bags contain 1 dim tan bag.\ndim tan bags contain 2 dim red bags.\n"
+                .parse()
+                .expect("rules");
+        let red = graph.id("dim red").expect("bag");
+        assert!(graph.contains(red, red));
+        assert!(graph.ancestors(red).contains(&red));
+        assert_eq!(graph.count_inside(red), None);
+        assert!("dim red bags hold 1 dim tan bag."
+            .parse::<BagGraph>()
+            .is_err());
+        assert_eq!(super::shiny_gold("dim red bags contain nothing."), 0);
+    }
 }
 mod parse {
-    use std::collections::HashMap;
-
     use nom::{
         branch::alt,
         bytes::complete::tag,
@@ -132,13 +264,16 @@ mod parse {
         Ok((input, count_description))
     }
 
-    fn get_bag(input: &str) -> IResult<&str, (String, HashMap<String, usize>)> {
+    /// A bag and what it holds directly, with how many of each.
+    pub type Rule = (String, Vec<(String, usize)>);
+
+    fn get_bag(input: &str) -> IResult<&str, Rule> {
         let (input, (description, capacity)) =
             separated_pair(get_description, tag(" bags contain "), get_content)(input)?;
         let (input, _done) = tag(".")(input)?;
-        Ok((input, (description, capacity.iter().cloned().collect())))
+        Ok((input, (description, capacity)))
     }
-    pub fn get_bags(input: &str) -> IResult<&str, Vec<(String, HashMap<String, usize>)>> {
+    pub fn get_bags(input: &str) -> IResult<&str, Vec<Rule>> {
         separated_list0(newline, get_bag)(input)
     }
     #[cfg(test)]
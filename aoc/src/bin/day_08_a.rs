@@ -1,10 +1,13 @@
-use tracing::info;
+use tracing::{error, info};
 
 fn main() {
     let input_path = aoc::setup::init_and_get_input();
 
-    let data = std::fs::read_to_string(input_path).expect("data");
-    let mut machine = aoc::intligen::load_machine(&data);
+    let data = std::fs::read(input_path).expect("data");
+    let mut machine = aoc::intligen::load_machine(&data).unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(1)
+    });
 
-    info!("Day 08a: {}", machine.run_to_first());
+    info!("Day 08a: {}", machine.run_to_first().accumulator());
 }
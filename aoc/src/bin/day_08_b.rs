@@ -1,10 +1,22 @@
-use tracing::info;
+use tracing::{error, info};
 
 fn main() {
     let input_path = aoc::setup::init_and_get_input();
 
-    let data = std::fs::read_to_string(input_path).expect("data");
-    let mut machine = aoc::intligen::load_machine(&data);
+    let data = std::fs::read(input_path).expect("data");
+    let machine = aoc::intligen::load_machine(&data).unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(1)
+    });
 
-    info!("Day 08b: {}", machine.fix_it());
+    match machine.repair_with_graph() {
+        Ok(repair) => {
+            info!(
+                "Patched {} from {:?} to {:?}",
+                repair.index, repair.before, repair.after
+            );
+            info!("Day 08b: {}", repair.accumulator);
+        }
+        Err(halt) => error!("No single swap repairs the program: {:?}", halt),
+    }
 }
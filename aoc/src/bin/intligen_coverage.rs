@@ -0,0 +1,14 @@
+use tracing::error;
+
+fn main() {
+    let input_path = aoc::setup::init_and_get_input();
+
+    let data = std::fs::read(input_path).expect("data");
+    let mut machine = aoc::intligen::load_machine(&data).unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(1)
+    });
+    machine.run_to_first();
+
+    println!("{}", aoc::intligen::coverage::Coverage::of(&machine));
+}
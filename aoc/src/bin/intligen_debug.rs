@@ -0,0 +1,20 @@
+use tracing::error;
+
+fn main() {
+    let input_path = aoc::setup::init_and_get_input();
+
+    let data = std::fs::read(input_path).expect("data");
+    let machine = aoc::intligen::load_machine(&data)
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            std::process::exit(1)
+        })
+        .with_history(10_000);
+
+    let mut debugger = aoc::intligen::debugger::Debugger::new(machine);
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    debugger
+        .run(stdin.lock(), stdout.lock())
+        .expect("debugger io");
+}
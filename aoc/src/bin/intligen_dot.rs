@@ -0,0 +1,16 @@
+use tracing::error;
+
+fn main() {
+    let input_path = aoc::setup::init_and_get_input();
+
+    let data = std::fs::read(input_path).expect("data");
+    let machine = aoc::intligen::load_machine(&data).unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(1)
+    });
+
+    print!(
+        "{}",
+        aoc::intligen::dot::render_with_loop(machine.program())
+    );
+}
@@ -1,144 +1,1462 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    time::Instant,
+};
 
-use tracing::{error, info};
+use tracing::info;
 
-#[derive(Debug, Clone)]
+use opcode::{Custom, Fault, Flow, Registry};
+pub use state::{Overflow, Register, State};
+
+pub mod binary;
+pub mod bytecode;
+pub mod cfg;
+pub mod coverage;
+pub mod cycle;
+pub mod debugger;
+pub mod dot;
+pub mod generate;
+pub mod opcode;
+pub mod optimize;
+pub mod parallel;
+pub mod scheduler;
+pub mod snapshot;
+pub mod state;
+pub mod trace;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Operation {
     Acc,
     Jmp,
     Nop,
+    /// `add r +N` adds `N` to register `r`.
+    Add(Register),
+    /// `ld r +N` copies memory cell `N` into register `r`.
+    Load(Register),
+    /// `st r +N` copies register `r` into memory cell `N`.
+    Store(Register),
+    /// `jr r +N` jumps by the value of register `r` plus `N`.
+    JumpRegister(Register),
+    /// `in r +N` reads the next input value plus `N` into register `r`.
+    In(Register),
+    /// `out r +N` writes the value of register `r` plus `N` to the output.
+    Out(Register),
+    /// `tgl +N` flips the instruction `N` away, see `Instruction::flipped`.
+    Toggle,
+    /// An opcode added through a `Registry`.
+    Custom(Custom),
 }
-#[derive(Debug, Clone)]
-pub struct Instruction(Operation, isize);
 
-pub enum Next {
-    Done(isize),
-    Step,
-    Seen(isize, isize, isize),
+impl Operation {
+    pub fn name(&self) -> &str {
+        match self {
+            Operation::Acc => "acc",
+            Operation::Jmp => "jmp",
+            Operation::Nop => "nop",
+            Operation::Add(_) => "add",
+            Operation::Load(_) => "ld",
+            Operation::Store(_) => "st",
+            Operation::JumpRegister(_) => "jr",
+            Operation::In(_) => "in",
+            Operation::Out(_) => "out",
+            Operation::Toggle => "tgl",
+            Operation::Custom(custom) => custom.0.name(),
+        }
+    }
+    /// The register operand, for the operations that have one.
+    pub fn register(&self) -> Option<Register> {
+        match self {
+            Operation::Add(register)
+            | Operation::Load(register)
+            | Operation::Store(register)
+            | Operation::JumpRegister(register)
+            | Operation::In(register)
+            | Operation::Out(register) => Some(*register),
+            _ => None,
+        }
+    }
+    /// The same operation working on `register` instead.
+    pub fn with_register(&self, register: Register) -> Operation {
+        match self {
+            Operation::Add(_) => Operation::Add(register),
+            Operation::Load(_) => Operation::Load(register),
+            Operation::Store(_) => Operation::Store(register),
+            Operation::JumpRegister(_) => Operation::JumpRegister(register),
+            Operation::In(_) => Operation::In(register),
+            Operation::Out(_) => Operation::Out(register),
+            other => other.clone(),
+        }
+    }
+    /// Whether the operation needs the accumulator as an `isize`. Custom
+    /// opcodes get the whole state, so they may.
+    fn reads_accumulator(&self) -> bool {
+        match self {
+            Operation::Store(register)
+            | Operation::JumpRegister(register)
+            | Operation::Out(register) => *register == Register::Accumulator,
+            Operation::Custom(_) => true,
+            _ => false,
+        }
+    }
+    /// Faults with `Fault::Overflow` when the operation needs the accumulator
+    /// while it has wrapped around, see `State::accumulator_wrapped`.
+    pub fn execute(&self, argument: isize, state: &mut State) -> Flow {
+        if state.accumulator_wrapped() && self.reads_accumulator() {
+            return Flow::Fault(Fault::Overflow);
+        }
+        match self {
+            Operation::Acc => state
+                .add(Register::Accumulator, argument)
+                .map_or(Flow::Fault(Fault::Overflow), |_| Flow::Next),
+            Operation::Jmp => Flow::Jump(argument),
+            // The program is not part of the state, `Machine::step` does the
+            // flipping.
+            Operation::Nop | Operation::Toggle => Flow::Next,
+            Operation::Add(register) => state
+                .add(*register, argument)
+                .map_or(Flow::Fault(Fault::Overflow), |_| Flow::Next),
+            Operation::Load(register) => match state.cell(argument) {
+                Some(value) => {
+                    let value = *value;
+                    state.set(*register, value);
+                    Flow::Next
+                }
+                None => Flow::Fault(Fault::BadAddress(argument)),
+            },
+            Operation::Store(register) => {
+                let value = state.get(*register);
+                match state.cell(argument) {
+                    Some(cell) => {
+                        *cell = value;
+                        Flow::Next
+                    }
+                    None => Flow::Fault(Fault::BadAddress(argument)),
+                }
+            }
+            Operation::JumpRegister(register) => {
+                match state.overflow.add(state.get(*register), argument) {
+                    Some(offset) => Flow::Jump(offset),
+                    None => Flow::Fault(Fault::Overflow),
+                }
+            }
+            Operation::In(register) => match state.input.front() {
+                Some(value) => match state.overflow.add(*value, argument) {
+                    Some(value) => {
+                        state.input.pop_front();
+                        state.set(*register, value);
+                        Flow::Next
+                    }
+                    None => Flow::Fault(Fault::Overflow),
+                },
+                None => Flow::Fault(Fault::NoInput),
+            },
+            Operation::Out(register) => match state.overflow.add(state.get(*register), argument) {
+                Some(value) => {
+                    state.output.push_back(value);
+                    Flow::Next
+                }
+                None => Flow::Fault(Fault::Overflow),
+            },
+            Operation::Custom(custom) => custom.0.execute(argument, state),
+        }
+    }
+    /// Every way execution may continue after this operation.
+    pub fn flows(&self, argument: isize) -> Vec<Flow> {
+        match self {
+            Operation::Acc
+            | Operation::Nop
+            | Operation::Add(_)
+            | Operation::Load(_)
+            | Operation::Store(_)
+            | Operation::In(_)
+            | Operation::Out(_)
+            | Operation::Toggle => vec![Flow::Next],
+            Operation::Jmp => vec![Flow::Jump(argument)],
+            Operation::JumpRegister(_) => vec![Flow::Indirect],
+            Operation::Custom(custom) => custom.0.flows(argument),
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Instruction(pub Operation, pub isize);
+
+impl Instruction {
+    /// The instruction with `jmp` and `nop` swapped, `None` for anything
+    /// else.
+    pub fn toggled(&self) -> Option<Instruction> {
+        match self.0 {
+            Operation::Jmp => Some(Instruction(Operation::Nop, self.1)),
+            Operation::Nop => Some(Instruction(Operation::Jmp, self.1)),
+            _ => None,
+        }
+    }
+    /// What `tgl` turns the instruction into: `jmp` and `nop` swap, and so
+    /// do `acc` and `tgl`. Anything else stays as it is.
+    pub fn flipped(&self) -> Instruction {
+        match self.0 {
+            Operation::Acc => Instruction(Operation::Toggle, self.1),
+            Operation::Toggle => Instruction(Operation::Acc, self.1),
+            _ => self.toggled().unwrap_or_else(|| self.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.register() {
+            Some(register) => write!(f, "{} {} {:+}", self.0, register, self.1),
+            None => write!(f, "{} {:+}", self.0, self.1),
+        }
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = String;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match parse::get_instruction(&Registry::default(), input) {
+            Ok(("", instruction)) => Ok(instruction),
+            Ok((remainder, _)) => Err(remainder.to_string()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+/// A whole program in the text format read by `load_machine`, one
+/// instruction per line.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Program(pub Vec<Instruction>);
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, instruction) in self.0.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", instruction)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Program {
+    type Err = String;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        parse::get_instructions(&Registry::default(), input)
+            .map(Program)
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl From<Vec<Instruction>> for Program {
+    fn from(instructions: Vec<Instruction>) -> Self {
+        Program(instructions)
+    }
+}
+
+/// Why a program could not be loaded. `line` and `column` count from 1,
+/// `text` is the whole line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+    pub message: String,
+}
+
+/// The message and position, then the line with a caret under the column.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )?;
+        writeln!(f, "{}", self.text)?;
+        write!(f, "{:>1$}", "^", self.column)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A single swapped instruction that lets the program run to completion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair {
+    pub index: usize,
+    pub before: Instruction,
+    pub after: Instruction,
+    pub accumulator: isize,
+}
+
+/// Why a `Machine` stopped running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Halt {
+    /// Ran to the position immediately after the last instruction, or ran
+    /// an opcode that halts.
+    Terminated { accumulator: isize },
+    /// Was about to run `entry` a second time. Once the program has changed
+    /// itself, or when it branches on the state, only coming back with the
+    /// same state and program counts. `cycle` lists the instructions of the
+    /// loop in the order they ran, starting at `entry`.
+    InfiniteLoop {
+        entry: usize,
+        cycle: Vec<usize>,
+        accumulator: isize,
+    },
+    /// The instruction at `program_counter` jumped to `target`, which is
+    /// before the first instruction or further than one past the last.
+    OutOfBounds {
+        program_counter: usize,
+        target: isize,
+        accumulator: isize,
+    },
+    /// Used up the instruction budget after running `steps` instructions.
+    OutOfFuel { steps: usize, accumulator: isize },
+    /// Passed the deadline after running `steps` instructions.
+    TimedOut { steps: usize, accumulator: isize },
+    /// The `in` at `program_counter` has nothing to read. Running again
+    /// after more input arrives carries on from there.
+    Blocked {
+        program_counter: usize,
+        accumulator: isize,
+    },
+    /// The instruction at `program_counter` could not run.
+    Fault {
+        program_counter: usize,
+        fault: Fault,
+        accumulator: isize,
+    },
+}
+
+impl Halt {
+    /// Wrapped around when the machine's `accumulator_wrapped` says so.
+    pub fn accumulator(&self) -> isize {
+        match self {
+            Halt::Terminated { accumulator }
+            | Halt::InfiniteLoop { accumulator, .. }
+            | Halt::OutOfBounds { accumulator, .. }
+            | Halt::OutOfFuel { accumulator, .. }
+            | Halt::TimedOut { accumulator, .. }
+            | Halt::Blocked { accumulator, .. }
+            | Halt::Fault { accumulator, .. } => *accumulator,
+        }
+    }
+    pub fn is_terminated(&self) -> bool {
+        matches!(self, Halt::Terminated { .. })
+    }
+}
+
+/// Where the machine has been, by program counter, a hash of the state and
+/// a hash of the program. Each of them lists the position in `Machine::path`
+/// of the step that left from there and the state, so that a collision is
+/// not taken for a loop. Programs are told apart with `Edits` instead.
+type Seen = HashMap<(usize, u64, u64), Vec<(usize, State)>>;
+
+/// Every instruction a `tgl` overwrote since `seen` was last cleared, as the
+/// position in `Machine::path` of the step, the index and the instruction
+/// that was there before.
+type Edits = Vec<(usize, usize, Instruction)>;
+
+fn hash_state(state: &State) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    state.hash(&mut hasher);
+    hasher.finish()
 }
+
+fn hash_instruction(index: usize, instruction: &Instruction) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (index, instruction).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The sum of every instruction's hash, so that replacing one instruction
+/// only needs the hashes of the old and the new one.
+fn hash_program(program: &[Instruction]) -> u64 {
+    program
+        .iter()
+        .enumerate()
+        .fold(0, |sum, (index, instruction)| {
+            sum.wrapping_add(hash_instruction(index, instruction))
+        })
+}
+
 pub struct Machine {
-    accumulator: isize,
-    program_counter: isize,
+    state: State,
+    program_counter: usize,
     program: Vec<Instruction>,
-    visited: HashSet<isize>,
+    visited: HashSet<usize>,
+    path: Vec<usize>,
+    /// Set once a `tgl` has changed the program. From then on `visited`
+    /// says nothing about loops and `seen` is used instead.
+    self_modified: bool,
+    /// Set while the program has a branch that depends on the state, which
+    /// also makes `visited` meaningless.
+    branches_on_state: bool,
+    seen: Seen,
+    edits: Edits,
+    /// `hash_program` of `program`, worked out the first time `seen` needs
+    /// it and kept up to date from then on.
+    program_hash: Option<u64>,
+    detect_loops: bool,
+    hits: Vec<usize>,
+    steps: usize,
+    fuel: Option<usize>,
+    deadline: Option<Instant>,
+    history: VecDeque<Undo>,
+    history_limit: usize,
+    trace: Option<trace::Trace>,
+}
+
+/// What a single step overwrote, so that `step_back` can put it back.
+#[derive(Debug, Clone)]
+struct Undo {
+    program_counter: usize,
+    state: State,
+    first_visit: bool,
+    /// The visits an `in` or `out` cleared.
+    forgotten: Option<(HashSet<usize>, Vec<usize>, Seen, Edits)>,
+    self_modified: bool,
+    /// The instruction a `tgl` overwrote, and where.
+    flipped: Option<(usize, Instruction)>,
+    /// Whether the step added the state it ended in to `seen`.
+    seen: bool,
 }
 
 impl Machine {
-    pub fn fix_it(&mut self) -> isize {
-        let mut visits: HashMap<isize, bool> = HashMap::default();
-        loop {
-            match self.step_toggle(&mut visits) {
-                Next::Done(result) => {
-                    info!("Done");
-                    return result;
+    pub fn new(program: Vec<Instruction>) -> Machine {
+        Machine {
+            state: State::default(),
+            program_counter: 0,
+            visited: HashSet::default(),
+            path: vec![],
+            self_modified: false,
+            branches_on_state: branches_on_state(&program),
+            seen: Seen::default(),
+            edits: Edits::default(),
+            program_hash: None,
+            detect_loops: true,
+            hits: vec![0; program.len()],
+            steps: 0,
+            fuel: None,
+            deadline: None,
+            history: VecDeque::default(),
+            history_limit: 0,
+            trace: None,
+            program,
+        }
+    }
+    /// Stops the machine with `Halt::OutOfFuel` once it has run `fuel`
+    /// instructions.
+    pub fn with_fuel(mut self, fuel: usize) -> Machine {
+        self.fuel = Some(fuel);
+        self
+    }
+    /// Stops the machine with `Halt::TimedOut` once `deadline` has passed.
+    pub fn with_deadline(mut self, deadline: Instant) -> Machine {
+        self.deadline = Some(deadline);
+        self
+    }
+    /// Adds `value` to the end of the input queue.
+    pub fn push_input(&mut self, value: isize) {
+        self.state.input.push_back(value);
+    }
+    /// Removes and returns everything written by `out` so far.
+    pub fn take_output(&mut self) -> Vec<isize> {
+        self.state.output.drain(..).collect()
+    }
+    /// Gives the machine `size` memory cells, all zero.
+    pub fn with_memory(mut self, size: usize) -> Machine {
+        self.state.memory = vec![0; size];
+        self
+    }
+    /// What arithmetic does when a result does not fit, `Overflow::Checked`
+    /// unless set here.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Machine {
+        self.state.overflow = overflow;
+        self
+    }
+    /// Never reports `Halt::InfiniteLoop`, for callers that look for loops
+    /// themselves. The instructions run are not remembered either.
+    pub fn without_loop_detection(mut self) -> Machine {
+        self.detect_loops = false;
+        self
+    }
+    /// Remembers the last `limit` steps so they can be undone with
+    /// `step_back`.
+    pub fn with_history(mut self, limit: usize) -> Machine {
+        self.history_limit = limit;
+        self
+    }
+    /// Records every step from now on, see `trace`.
+    pub fn with_trace(mut self) -> Machine {
+        self.trace = Some(trace::Trace::default());
+        self
+    }
+    /// A fresh machine running `program` under the same limits as this one,
+    /// with as much memory and the same input waiting.
+    fn with_program(&self, program: Vec<Instruction>) -> Machine {
+        let mut machine = Machine {
+            fuel: self.fuel,
+            deadline: self.deadline,
+            ..Machine::new(program)
+        }
+        .with_memory(self.state.memory.len())
+        .with_overflow(self.state.overflow);
+        machine.state.input = self.state.input.clone();
+        machine
+    }
+    pub fn accumulator(&self) -> isize {
+        self.state.accumulator
+    }
+    /// The accumulator with nothing lost to overflow, see
+    /// `Overflow::Unbounded`.
+    pub fn exact_accumulator(&self) -> num_bigint::BigInt {
+        self.state.exact_accumulator()
+    }
+    /// Whether `accumulator`, and the accumulator in the `Halt` the machine
+    /// stopped with, have wrapped around and only `exact_accumulator` is
+    /// right.
+    pub fn accumulator_wrapped(&self) -> bool {
+        self.state.accumulator_wrapped()
+    }
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+    /// Number of instructions run so far.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+    /// How many times each instruction has run.
+    pub fn hits(&self) -> &[usize] {
+        &self.hits
+    }
+    /// The steps recorded so far, if the machine was made `with_trace`.
+    pub fn trace(&self) -> Option<&trace::Trace> {
+        self.trace.as_ref()
+    }
+    /// Replaces the instruction at `index`, returning the one it replaced.
+    /// The states compared to find loops were reached with the old program,
+    /// so they are forgotten.
+    pub fn patch(&mut self, index: usize, instruction: Instruction) -> Option<Instruction> {
+        if index >= self.program.len() {
+            return None;
+        }
+        let before = self.replace(index, instruction);
+        self.branches_on_state = branches_on_state(&self.program);
+        self.seen.clear();
+        self.edits.clear();
+        Some(before)
+    }
+    /// Puts `instruction` at `index`, keeping `program_hash` up to date.
+    fn replace(&mut self, index: usize, instruction: Instruction) -> Instruction {
+        if let Some(hash) = self.program_hash.as_mut() {
+            *hash = hash
+                .wrapping_sub(hash_instruction(index, &self.program[index]))
+                .wrapping_add(hash_instruction(index, &instruction));
+        }
+        std::mem::replace(&mut self.program[index], instruction)
+    }
+    /// Where the machine is, for `seen`.
+    fn fingerprint(&mut self) -> (usize, u64, u64) {
+        let program = &self.program;
+        let program_hash = *self
+            .program_hash
+            .get_or_insert_with(|| hash_program(program));
+        (self.program_counter, hash_state(&self.state), program_hash)
+    }
+    /// Whether the program is the one the machine had before the step at
+    /// `position` in `path`, going by what `tgl` overwrote since.
+    fn same_program_since(&self, position: usize) -> bool {
+        let start = self.edits.partition_point(|(step, _, _)| *step < position);
+        let mut checked = HashSet::new();
+        self.edits[start..]
+            .iter()
+            .all(|(_, index, before)| !checked.insert(*index) || self.program[*index] == *before)
+    }
+    /// Tries every single `jmp`/`nop` swap and returns the first one that
+    /// makes the program run past its last instruction. Instructions the
+    /// unpatched run never reaches are skipped, swapping them changes nothing.
+    /// When the program already terminates, or no swap helps, returns how it
+    /// halts unpatched.
+    pub fn repair(&self) -> Result<Repair, Halt> {
+        let mut unpatched = self.with_program(self.program.clone());
+        let original = unpatched.run();
+        if original.is_terminated() {
+            return Err(original);
+        }
+        let found = self.program.iter().enumerate().find_map(|(index, before)| {
+            if unpatched.hits[index] == 0 {
+                return None;
+            }
+            let after = before.toggled()?;
+            let mut program = self.program.clone();
+            program[index] = after.clone();
+            match self.with_program(program).run() {
+                Halt::Terminated { accumulator } => {
+                    info!("Swapping {} made the program terminate", index);
+                    Some(Repair {
+                        index,
+                        before: before.clone(),
+                        after,
+                        accumulator,
+                    })
                 }
-                Next::Step => (),
-                Next::Seen(old_pc, next_pc, old_acc) => {
-                    info!(
-                        "At {} before revisit at {}, the accumulator was {}",
-                        old_pc, next_pc, old_acc
-                    );
-                    return old_acc;
+                _ => None,
+            }
+        });
+        found.ok_or(original)
+    }
+    /// Like `repair`, but picks the swap from the control-flow graph and only
+    /// runs the patched program once. The graph is wrong for programs that
+    /// use `tgl` or branch on the state, and has every `ld`, `st` and `in`
+    /// carry on where they may fault or block, so those programs are left to
+    /// `repair`. So is any program where the graph's answer does not hold
+    /// up, as when an `acc` overflows.
+    pub fn repair_with_graph(&self) -> Result<Repair, Halt> {
+        if branches_on_state(&self.program)
+            || self.program.iter().any(|instruction| {
+                matches!(
+                    instruction.0,
+                    Operation::Toggle | Operation::Load(_) | Operation::Store(_) | Operation::In(_)
+                )
+            })
+        {
+            return self.repair();
+        }
+        let graph = cfg::ControlFlowGraph::new(&self.program);
+        let index = match graph.repair_index(&self.program) {
+            Some(index) => index,
+            None => {
+                let halt = self.with_program(self.program.clone()).run();
+                // Unless the graph took a program that faults for one that
+                // terminates, no swap helps.
+                if halt.is_terminated() || !graph.reaches_exit().contains(&0) {
+                    return Err(halt);
                 }
+                return self.repair();
             }
+        };
+        let before = self.program[index].clone();
+        let after = before
+            .toggled()
+            .expect("repair_index only returns jmp or nop");
+        let mut program = self.program.clone();
+        program[index] = after.clone();
+        match self.with_program(program).run() {
+            Halt::Terminated { accumulator } => Ok(Repair {
+                index,
+                before,
+                after,
+                accumulator,
+            }),
+            _ => self.repair(),
         }
-        // pc -> vec (count, acc)
     }
-
-    fn step_toggle(&mut self, visits: &mut HashMap<isize, bool>) -> Next {
-        self.visited.insert(self.program_counter);
-        if let Some(next_op) = self.program.get(self.program_counter as usize).cloned() {
-            let (next_pc, next_acc) = match next_op.0 {
-                Operation::Acc => (self.program_counter + 1, self.accumulator + next_op.1),
-                Operation::Jmp => (self.program_counter + next_op.1, self.accumulator),
-                Operation::Nop => (self.program_counter + 1, self.accumulator),
-            };
-            if self.visited.contains(&next_pc) {
-                Next::Seen(self.program_counter, next_pc, self.accumulator)
-            } else {
-                self.accumulator = next_acc;
-                self.program_counter = next_pc;
-                Next::Step
+    /// Runs a single instruction, or returns why the machine cannot.
+    pub fn step(&mut self) -> Option<Halt> {
+        let instruction = match self.program.get(self.program_counter) {
+            Some(instruction) => instruction,
+            None => {
+                info!("No operation at: {}", self.program_counter);
+                return Some(Halt::Terminated {
+                    accumulator: self.state.accumulator,
+                });
             }
-        } else {
-            info!("No operation at: {}", self.program_counter);
-            Next::Done(self.accumulator)
-        }
-    }
-    fn step(&mut self) -> Next {
-        self.visited.insert(self.program_counter);
-        if let Some(next_op) = self.program.get(self.program_counter as usize).cloned() {
-            let (next_pc, next_acc) = match next_op.0 {
-                Operation::Acc => (self.program_counter + 1, self.accumulator + next_op.1),
-                Operation::Jmp => (self.program_counter + next_op.1, self.accumulator),
-                Operation::Nop => (self.program_counter + 1, self.accumulator),
-            };
-            if self.visited.contains(&next_pc) {
-                Next::Seen(self.program_counter, next_pc, self.accumulator)
-            } else {
-                self.accumulator = next_acc;
-                self.program_counter = next_pc;
-                Next::Step
+        };
+        if self.fuel.is_some_and(|fuel| self.steps >= fuel) {
+            return Some(Halt::OutOfFuel {
+                steps: self.steps,
+                accumulator: self.state.accumulator,
+            });
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Some(Halt::TimedOut {
+                steps: self.steps,
+                accumulator: self.state.accumulator,
+            });
+        }
+        if let Operation::In(_) = instruction.0 {
+            if self.state.input.is_empty() {
+                return Some(Halt::Blocked {
+                    program_counter: self.program_counter,
+                    accumulator: self.state.accumulator,
+                });
             }
-        } else {
-            info!("No operation at: {}", self.program_counter);
-            Next::Done(self.accumulator)
         }
+        if self.history_limit > 0 {
+            if self.history.len() == self.history_limit {
+                self.history.pop_front();
+            }
+            self.history.push_back(Undo {
+                program_counter: self.program_counter,
+                state: self.state.clone(),
+                first_visit: !self.visited.contains(&self.program_counter),
+                forgotten: None,
+                self_modified: self.self_modified,
+                flipped: None,
+                seen: false,
+            });
+        }
+        self.steps += 1;
+        self.hits[self.program_counter] += 1;
+        if self.detect_loops {
+            self.visited.insert(self.program_counter);
+            self.path.push(self.program_counter);
+        }
+        let accumulator_before = self.state.accumulator;
+        let flow = instruction.0.execute(instruction.1, &mut self.state);
+        if let Operation::In(_) | Operation::Out(_) = instruction.0 {
+            // Input and output are progress, so coming back to an
+            // instruction seen before no longer means the program is stuck.
+            let forgotten = (
+                std::mem::take(&mut self.visited),
+                std::mem::take(&mut self.path),
+                std::mem::take(&mut self.seen),
+                std::mem::take(&mut self.edits),
+            );
+            if let Some(undo) = self.history.back_mut() {
+                undo.forgotten = Some(forgotten);
+            }
+        }
+        let instruction = instruction.clone();
+        if instruction.0 == Operation::Toggle {
+            let index = (self.program_counter as isize).saturating_add(instruction.1);
+            // Like a patch, flipping something that is not there does nothing.
+            if index >= 0 && (index as usize) < self.program.len() {
+                let index = index as usize;
+                let before = self.replace(index, self.program[index].flipped());
+                self.self_modified = true;
+                if self.detect_loops {
+                    self.edits
+                        .push((self.path.len() - 1, index, before.clone()));
+                }
+                if let Some(undo) = self.history.back_mut() {
+                    undo.flipped = Some((index, before));
+                }
+            }
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(trace::TraceEntry {
+                step: self.steps,
+                program_counter: self.program_counter,
+                instruction,
+                accumulator_before,
+                accumulator_after: self.state.accumulator,
+            });
+        }
+        let target = match flow {
+            Flow::Next => self.program_counter as isize + 1,
+            // Far enough either way to be out of bounds, without overflowing.
+            Flow::Jump(offset) => (self.program_counter as isize).saturating_add(offset),
+            Flow::Halt => {
+                return Some(Halt::Terminated {
+                    accumulator: self.state.accumulator,
+                })
+            }
+            Flow::Fault(fault) => {
+                return Some(Halt::Fault {
+                    program_counter: self.program_counter,
+                    fault,
+                    accumulator: self.state.accumulator,
+                })
+            }
+            Flow::Indirect => {
+                return Some(Halt::Fault {
+                    program_counter: self.program_counter,
+                    fault: Fault::Indirect,
+                    accumulator: self.state.accumulator,
+                })
+            }
+        };
+        if target < 0 || target as usize > self.program.len() {
+            return Some(Halt::OutOfBounds {
+                program_counter: self.program_counter,
+                target,
+                accumulator: self.state.accumulator,
+            });
+        }
+        self.program_counter = target as usize;
+        if !self.detect_loops {
+            return None;
+        }
+        if self.self_modified || self.branches_on_state {
+            let key = self.fingerprint();
+            let start = self.seen.get(&key).and_then(|visits| {
+                visits.iter().find_map(|(start, state)| {
+                    (*state == self.state && self.same_program_since(*start)).then_some(*start)
+                })
+            });
+            if let Some(start) = start {
+                return Some(Halt::InfiniteLoop {
+                    entry: self.program_counter,
+                    cycle: self.path[start..].to_vec(),
+                    accumulator: self.state.accumulator,
+                });
+            }
+            self.seen
+                .entry(key)
+                .or_default()
+                .push((self.path.len(), self.state.clone()));
+            if let Some(undo) = self.history.back_mut() {
+                undo.seen = true;
+            }
+        } else if self.visited.contains(&self.program_counter) {
+            let start = self
+                .path
+                .iter()
+                .position(|pc| *pc == self.program_counter)
+                .unwrap_or_default();
+            return Some(Halt::InfiniteLoop {
+                entry: self.program_counter,
+                cycle: self.path[start..].to_vec(),
+                accumulator: self.state.accumulator,
+            });
+        }
+        None
     }
-    pub fn run_to_first(&mut self) -> isize {
-        loop {
-            match self.step() {
-                Next::Done(result) => {
-                    info!("Done");
-                    return result;
+    /// Undoes the most recent step still in the history. Returns `false`
+    /// when there is nothing left to undo.
+    pub fn step_back(&mut self) -> bool {
+        match self.history.pop_back() {
+            Some(undo) => {
+                if undo.seen {
+                    let key = self.fingerprint();
+                    let len = self.path.len();
+                    if let Some(visits) = self.seen.get_mut(&key) {
+                        visits.retain(|(position, _)| *position != len);
+                        if visits.is_empty() {
+                            self.seen.remove(&key);
+                        }
+                    }
+                }
+                if let Some((index, instruction)) = undo.flipped {
+                    self.replace(index, instruction);
+                    if self.edits.last().is_some_and(|(step, edited, _)| {
+                        *step + 1 == self.path.len() && *edited == index
+                    }) {
+                        self.edits.pop();
+                    }
                 }
-                Next::Step => (),
-                Next::Seen(old_pc, next_pc, old_acc) => {
-                    info!(
-                        "At {} before revisit at {}, the accumulator was {}",
-                        old_pc, next_pc, old_acc
-                    );
-                    return old_acc;
+                self.self_modified = undo.self_modified;
+                if let Some((visited, path, seen, edits)) = undo.forgotten {
+                    self.visited = visited;
+                    self.path = path;
+                    self.seen = seen;
+                    self.edits = edits;
                 }
+                if undo.first_visit {
+                    self.visited.remove(&undo.program_counter);
+                }
+                self.path.pop();
+                self.hits[undo.program_counter] -= 1;
+                if let Some(trace) = self.trace.as_mut() {
+                    trace.pop(self.steps);
+                }
+                self.steps -= 1;
+                self.program_counter = undo.program_counter;
+                self.state = undo.state;
+                true
             }
+            None => false,
         }
-        // pc -> vec (count, acc)
     }
-}
-
-pub fn load_machine(input: &str) -> Machine {
-    let program = match parse::get_instructions(input) {
-        Ok((remainder, instructions)) => {
-            if remainder.is_empty() {
-                info!("Loaded {} instructions.", instructions.len());
-                instructions
-            } else {
-                error!("{}", remainder);
-                vec![]
+    fn run(&mut self) -> Halt {
+        loop {
+            if let Some(halt) = self.step() {
+                return halt;
             }
         }
-        Err(err) => {
-            error!("{}", err);
-            vec![]
+    }
+    pub fn run_to_first(&mut self) -> Halt {
+        let halt = self.run();
+        match &halt {
+            Halt::InfiniteLoop {
+                entry, accumulator, ..
+            } => info!(
+                "Before revisit at {}, the accumulator was {}",
+                entry, accumulator
+            ),
+            Halt::OutOfBounds {
+                program_counter,
+                target,
+                ..
+            } => info!("Jump from {} out of bounds to {}", program_counter, target),
+            Halt::OutOfFuel { steps, .. } | Halt::TimedOut { steps, .. } => {
+                info!("Gave up after {} steps", steps)
+            }
+            _ => info!("Done"),
         }
-    };
-    Machine {
-        accumulator: 0,
-        program_counter: 0,
-        visited: HashSet::default(),
-        program,
+        halt
     }
 }
 
+/// Whether any instruction may continue in more than one place, depending on
+/// the state. Loop detection by program counter only works without those.
+fn branches_on_state(program: &[Instruction]) -> bool {
+    program.iter().any(|Instruction(operation, argument)| {
+        let flows = operation.flows(*argument);
+        flows.len() > 1 || flows.contains(&Flow::Indirect)
+    })
+}
+
+/// Loads a program in the text format, or in the `binary` one when `input`
+/// starts with `binary::MAGIC`.
+pub fn load_machine(input: impl AsRef<[u8]>) -> Result<Machine, ParseError> {
+    load_machine_with(&Registry::default(), input)
+}
+
+/// Like `load_machine`, but accepts every opcode in `registry`.
+pub fn load_machine_with(
+    registry: &Registry,
+    input: impl AsRef<[u8]>,
+) -> Result<Machine, ParseError> {
+    let input = input.as_ref();
+    let program = if binary::is_binary(input) {
+        binary::decode(registry, input)?
+    } else {
+        parse::get_instructions(registry, text(input)?)?
+    };
+    info!("Loaded {} instructions.", program.len());
+    Ok(Machine::new(program))
+}
+
+/// `input` as text, or an error pointing at the first byte that is not
+/// UTF-8.
+fn text(input: &[u8]) -> Result<&str, ParseError> {
+    std::str::from_utf8(input).map_err(|err| {
+        let valid = &input[..err.valid_up_to()];
+        let start = valid
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |newline| newline + 1);
+        let end = input[start..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(input.len(), |newline| start + newline);
+        ParseError {
+            line: valid.iter().filter(|byte| **byte == b'\n').count() + 1,
+            column: String::from_utf8_lossy(&valid[start..]).chars().count() + 1,
+            text: String::from_utf8_lossy(&input[start..end]).into_owned(),
+            message: "expected UTF-8 text".to_string(),
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
+    use super::{
+        opcode::{Fault, Jz, Mul, Registry},
+        Halt, Instruction, Operation, Overflow, Program, Register, Repair,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::time::{Duration, Instant};
+
+    pub fn random_program(rng: &mut StdRng) -> Vec<Instruction> {
+        super::generate::Generator::default().generate(rng)
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Instruction(Operation::Acc, 6).to_string(), "acc +6");
+        assert_eq!(Instruction(Operation::Jmp, -3).to_string(), "jmp -3");
+        assert_eq!(Instruction(Operation::Nop, 0).to_string(), "nop +0");
+        let program: Program = PROGRAM.parse().expect("program");
+        assert_eq!(program.to_string(), PROGRAM);
+        assert!("nop +0\nhop +1".parse::<Program>().is_err());
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let program = "add a +3\nst a +2\nld acc +2\nadd b +2\njr b +0\nacc +100\nacc +1";
+        let parsed: Program = program.parse().expect("program");
+        assert_eq!(
+            parsed.0[4],
+            Instruction(Operation::JumpRegister(Register::Named(1)), 0)
+        );
+        assert_eq!(parsed.to_string(), program);
+
+        let mut machine = super::load_machine(program)
+            .expect("program")
+            .with_memory(4);
+        assert_eq!(machine.run_to_first(), Halt::Terminated { accumulator: 4 });
+        assert_eq!(machine.state().get(Register::Named(0)), 3);
+        assert_eq!(machine.state().memory, vec![0, 0, 3, 0]);
+    }
+
+    #[test]
+    fn flip() {
+        let flipped = |text: &str| {
+            text.parse::<Instruction>()
+                .expect("instruction")
+                .flipped()
+                .to_string()
+        };
+        assert_eq!(flipped("jmp -2"), "nop -2");
+        assert_eq!(flipped("nop +0"), "jmp +0");
+        assert_eq!(flipped("acc +3"), "tgl +3");
+        assert_eq!(flipped("tgl -1"), "acc -1");
+        assert_eq!(flipped("add b +1"), "add b +1");
+    }
+
+    #[test]
+    fn toggle_is_not_a_loop() {
+        let program = "acc +1\ntgl +1\nnop -2";
+        let mut machine = super::load_machine(program)
+            .expect("program")
+            .with_history(20);
+        let start = machine.snapshot();
+        assert_eq!(machine.run_to_first(), Halt::Terminated { accumulator: 2 });
+        assert_eq!(machine.steps(), 6);
+        assert_eq!(machine.program()[2], Instruction(Operation::Nop, -2));
+        while machine.step_back() {}
+        assert_eq!(machine.snapshot(), start);
+    }
+
+    #[test]
+    fn toggle_loop() {
+        let mut machine = super::load_machine("tgl +1\nnop +1\njmp -2").expect("program");
+        assert_eq!(
+            machine.run_to_first(),
+            Halt::InfiniteLoop {
+                entry: 1,
+                cycle: vec![1, 2, 0, 1, 2, 0],
+                accumulator: 0
+            }
+        );
+        // Out of range, so nothing to flip and nothing modified.
+        let mut machine = super::load_machine("tgl +7\njmp -1").expect("program");
+        assert_eq!(
+            machine.run_to_first(),
+            Halt::InfiniteLoop {
+                entry: 0,
+                cycle: vec![0, 1],
+                accumulator: 0
+            }
+        );
+    }
+
+    #[test]
+    fn blocked_on_input() {
+        let mut machine = super::load_machine("in a +0\nout a +0\njmp -2").expect("program");
+        assert_eq!(
+            machine.run_to_first(),
+            Halt::Blocked {
+                program_counter: 0,
+                accumulator: 0
+            }
+        );
+        assert_eq!(machine.steps(), 0);
+        for value in 1..=3 {
+            machine.push_input(value);
+        }
+        assert_eq!(
+            machine.run_to_first(),
+            Halt::Blocked {
+                program_counter: 0,
+                accumulator: 0
+            }
+        );
+        assert_eq!(machine.take_output(), vec![1, 2, 3]);
+        assert!(machine.take_output().is_empty());
+    }
+
+    #[test]
+    fn step_back_over_io() {
+        let mut machine = super::load_machine("in a +0\nout a +0\njmp -2")
+            .expect("program")
+            .with_history(10);
+        machine.push_input(4);
+        let start = machine.snapshot();
+        while machine.step().is_none() {}
+        while machine.step_back() {}
+        assert_eq!(machine.snapshot(), start);
+    }
+
+    #[test]
+    fn bad_address() {
+        let mut machine = super::load_machine("acc +1\nld a +4")
+            .expect("program")
+            .with_memory(4);
+        assert_eq!(
+            machine.run_to_first(),
+            Halt::Fault {
+                program_counter: 1,
+                fault: Fault::BadAddress(4),
+                accumulator: 1
+            }
+        );
+        let mut machine = super::load_machine("st a -1").expect("program");
+        assert!(matches!(machine.run_to_first(), Halt::Fault { .. }));
+    }
+
+    #[test]
+    fn overflow() {
+        let program = "acc +9223372036854775807\nacc +2\nacc -3";
+        let run = |overflow| {
+            let mut machine = super::load_machine(program)
+                .expect("program")
+                .with_overflow(overflow);
+            (machine.run_to_first(), machine.exact_accumulator())
+        };
+        let max = isize::MAX;
+        assert_eq!(
+            run(Overflow::Checked),
+            (
+                Halt::Fault {
+                    program_counter: 1,
+                    fault: Fault::Overflow,
+                    accumulator: max
+                },
+                max.into()
+            )
+        );
+        let wrapped = max.wrapping_add(2).wrapping_sub(3);
+        assert_eq!(
+            run(Overflow::Wrapping),
+            (
+                Halt::Terminated {
+                    accumulator: wrapped
+                },
+                wrapped.into()
+            )
+        );
+        assert_eq!(
+            run(Overflow::Saturating),
+            (
+                Halt::Terminated {
+                    accumulator: max - 3
+                },
+                (max - 3).into()
+            )
+        );
+        assert_eq!(
+            run(Overflow::Unbounded),
+            (
+                Halt::Terminated {
+                    accumulator: wrapped
+                },
+                (max - 1).into()
+            )
+        );
+
+        let mut machine = super::load_machine("acc +9223372036854775807\nacc +9223372036854775807")
+            .expect("program")
+            .with_overflow(Overflow::Unbounded);
+        assert!(!machine.accumulator_wrapped());
+        assert_eq!(machine.run_to_first(), Halt::Terminated { accumulator: -2 });
+        assert!(machine.accumulator_wrapped());
+        assert_eq!(
+            machine.exact_accumulator().to_string(),
+            "18446744073709551614"
+        );
+        // Wrapping is what was asked for, so nothing is lost.
+        let mut machine = super::load_machine("acc +9223372036854775807\nacc +9223372036854775807")
+            .expect("program")
+            .with_overflow(Overflow::Wrapping);
+        assert_eq!(machine.run_to_first(), Halt::Terminated { accumulator: -2 });
+        assert!(!machine.accumulator_wrapped());
+
+        let mut machine = super::load_machine("add a -9223372036854775807\nadd a -2\nout a -1")
+            .expect("program")
+            .with_overflow(Overflow::Unbounded);
+        assert!(matches!(
+            machine.run_to_first(),
+            Halt::Fault {
+                program_counter: 1,
+                fault: Fault::Overflow,
+                ..
+            }
+        ));
+        let mut machine = super::load_machine("add a -9223372036854775807\nadd a -2\nout a -1")
+            .expect("program")
+            .with_overflow(Overflow::Saturating);
+        assert_eq!(machine.run_to_first(), Halt::Terminated { accumulator: 0 });
+        assert_eq!(machine.take_output(), vec![isize::MIN]);
+
+        let mut machine = super::load_machine("nop +0\njmp +9223372036854775807").expect("program");
+        assert_eq!(
+            machine.run_to_first(),
+            Halt::OutOfBounds {
+                program_counter: 1,
+                target: isize::MAX,
+                accumulator: 0
+            }
+        );
+    }
+
+    #[test]
+    fn wrapped_accumulator() {
+        let mut registry = Registry::default();
+        registry.register(Jz).register(Mul);
+        // Exactly 2^64, which is 0 once wrapped.
+        let wrapped = "acc +9223372036854775807\nacc +9223372036854775807\nacc +2\n";
+        for reader in &["jz +2", "mul +1", "out acc +0", "st acc +0", "jr acc +1"] {
+            let program = format!("{}{}\nacc +1", wrapped, reader);
+            let mut machine = super::load_machine_with(&registry, &program)
+                .expect("program")
+                .with_memory(1)
+                .with_overflow(Overflow::Unbounded);
+            assert_eq!(
+                machine.run_to_first(),
+                Halt::Fault {
+                    program_counter: 3,
+                    fault: Fault::Overflow,
+                    accumulator: 0
+                },
+                "{}",
+                reader
+            );
+            assert!(machine.take_output().is_empty());
+            assert_eq!(machine.state().memory, vec![0]);
+        }
+
+        let mut machine =
+            super::load_machine("acc +9223372036854775807\nacc +1\nacc -1\nout acc +0")
+                .expect("program")
+                .with_overflow(Overflow::Unbounded);
+        assert!(machine.run_to_first().is_terminated());
+        assert_eq!(machine.take_output(), vec![isize::MAX]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| super::load_machine(text).err().expect("error");
+        let unknown = error("acc +1\njnz +3\nnop +0");
+        assert_eq!((unknown.line, unknown.column), (2, 1));
+        assert_eq!(
+            unknown.to_string(),
+            "unknown opcode `jnz` at line 2, column 1\njnz +3\n^"
+        );
+        let utf8 = super::load_machine(b"nop +0\nacc +\xff1")
+            .err()
+            .expect("error");
+        assert_eq!((utf8.line, utf8.column), (2, 6));
+        assert_eq!(utf8.text, "acc +\u{fffd}1");
+        let argument = error("acc +x3");
+        assert_eq!(argument.column, 5);
+        assert_eq!(
+            argument.message,
+            "expected an argument like `+3` or `-7` for `acc`, found `+x3`"
+        );
+        assert_eq!(argument.to_string().lines().last(), Some("    ^"));
+        let register = error("add Q +1");
+        assert_eq!(register.column, 5);
+        assert!(register.message.starts_with("expected a register"));
+        let trailing = error("acc +1 +2");
+        assert_eq!(trailing.column, 7);
+        assert_eq!(trailing.message, "unexpected ` +2` after the instruction");
+        assert_eq!(error("nop +0\nacc").column, 4);
+
+        assert_eq!(
+            "nop +0\nhop +1".parse::<Program>(),
+            Err(unknown
+                .to_string()
+                .replace("jnz +3", "hop +1")
+                .replace("jnz", "hop"))
+        );
+        assert!(super::load_machine("").is_ok());
+    }
+
+    #[test]
+    fn labels_and_comments() {
+        let source = "; skip the trap\r
+        jmp over
+trap:   acc -99
+over:   acc +1  ; counted
+
+        nop trap
+end:
+";
+        let program: Program = source.parse().expect("program");
+        assert_eq!(program.to_string(), "jmp +2\nacc -99\nacc +1\nnop -2");
+        let mut machine = super::load_machine(source).expect("program");
+        assert_eq!(machine.run_to_first(), Halt::Terminated { accumulator: 1 });
+        let program: Program = "jmp end\nacc +1\nend:".parse().expect("program");
+        assert_eq!(
+            program.0,
+            vec![
+                Instruction(Operation::Jmp, 2),
+                Instruction(Operation::Acc, 1)
+            ]
+        );
+
+        let error = |text: &str| super::load_machine(text).err().expect("error");
+        let unknown = error("acc +1\n  jmp there");
+        assert_eq!((unknown.line, unknown.column), (2, 7));
+        assert_eq!(unknown.message, "unknown label `there`");
+        let twice = error("here: nop +0\n here: acc +1");
+        assert_eq!((twice.line, twice.column), (2, 2));
+        assert_eq!(twice.message, "label `here` is already defined at line 1");
+        assert_eq!(error("x:    acc +y3").column, 11);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = StdRng::seed_from_u64(6);
+        for _ in 0..500 {
+            let program = Program(random_program(&mut rng));
+            let text = program.to_string();
+            let parsed: Program = text.parse().expect("parse");
+            assert_eq!(parsed, program);
+            assert_eq!(parsed.to_string(), text);
+        }
+    }
     #[test]
     fn run_machine() {
-        let mut machine = super::load_machine(PROGRAM);
-        assert_eq!(machine.run_to_first(), 5);
+        let mut machine = super::load_machine(PROGRAM).expect("program");
+        assert_eq!(
+            machine.run_to_first(),
+            Halt::InfiniteLoop {
+                entry: 1,
+                cycle: vec![1, 2, 6, 7, 3, 4],
+                accumulator: 5
+            }
+        );
+    }
+
+    #[test]
+    fn run_out_of_bounds() {
+        let mut machine = super::load_machine("acc +2\njmp -2").expect("program");
+        assert_eq!(
+            machine.run_to_first(),
+            Halt::OutOfBounds {
+                program_counter: 1,
+                target: -1,
+                accumulator: 2
+            }
+        );
+        let mut machine = super::load_machine("nop +0\njmp +2").expect("program");
+        assert!(!machine.run_to_first().is_terminated());
+        let mut machine = super::load_machine("nop +0\njmp +1").expect("program");
+        assert_eq!(machine.run_to_first(), Halt::Terminated { accumulator: 0 });
+    }
+
+    #[test]
+    fn run_out_of_fuel() {
+        let mut machine = super::load_machine("nop +0\nacc +1\nacc +1\nacc +1")
+            .expect("program")
+            .with_fuel(3);
+        assert_eq!(
+            machine.run_to_first(),
+            Halt::OutOfFuel {
+                steps: 3,
+                accumulator: 2
+            }
+        );
+        assert_eq!(machine.steps(), 3);
+        let mut machine = super::load_machine("nop +0\nacc +1")
+            .expect("program")
+            .with_fuel(2);
+        assert!(machine.run_to_first().is_terminated());
+    }
+
+    #[test]
+    fn run_past_deadline() {
+        let mut machine = super::load_machine(PROGRAM)
+            .expect("program")
+            .with_deadline(Instant::now());
+        assert_eq!(
+            machine.run_to_first(),
+            Halt::TimedOut {
+                steps: 0,
+                accumulator: 0
+            }
+        );
+        let machine = super::load_machine(PROGRAM)
+            .expect("program")
+            .with_deadline(Instant::now() + Duration::from_secs(60));
+        assert_eq!(machine.repair().map(|repair| repair.accumulator), Ok(8));
+    }
+
+    #[test]
+    fn loop_counts_the_last_acc() {
+        let mut machine = super::load_machine("jmp +2\nacc +5\nacc +1\njmp -2").expect("program");
+        assert_eq!(machine.run_to_first().accumulator(), 6);
+    }
+
+    #[test]
+    fn repair_machine() {
+        let machine = super::load_machine(PROGRAM).expect("program");
+        let repair = machine.repair().expect("repair");
+        assert_eq!(repair.index, 7);
+        assert_eq!(repair.before, Instruction(Operation::Jmp, -4));
+        assert_eq!(repair.after, Instruction(Operation::Nop, -4));
+        assert_eq!(repair.accumulator, 8);
+    }
+
+    #[test]
+    fn repair_with_memory() {
+        let machine = super::load_machine("add a +5\nst a +0\nld acc +0\njmp +0\nacc +1")
+            .expect("program")
+            .with_memory(1);
+        let expected = Ok(Repair {
+            index: 3,
+            before: Instruction(Operation::Jmp, 0),
+            after: Instruction(Operation::Nop, 0),
+            accumulator: 6,
+        });
+        assert_eq!(machine.repair(), expected);
+        assert_eq!(machine.repair_with_graph(), expected);
+        assert_eq!(machine.repair_parallel(2), expected);
+
+        // Swapping the `jmp` runs into a `ld` that faults, which is not what
+        // the unpatched program does.
+        let machine = super::load_machine("nop +0\njmp +0\nld a +5\nacc +1")
+            .expect("program")
+            .with_memory(1);
+        let expected = machine.repair();
+        assert!(matches!(expected, Err(Halt::InfiniteLoop { .. })));
+        assert_eq!(machine.repair_with_graph(), expected);
+        assert_eq!(machine.repair_parallel(2), expected);
+
+        let machine = super::load_machine("jr a +1\njmp +0\nacc +1").expect("program");
+        let repair = machine.repair().expect("repair");
+        assert_eq!(repair.index, 1);
+        assert_eq!(machine.repair_with_graph(), Ok(repair.clone()));
+        assert_eq!(machine.repair_parallel(2), Ok(repair));
     }
 
-    const PROGRAM: &str = r#"nop +0
+    #[test]
+    fn repair_with_input() {
+        let mut machine = super::load_machine("in a +0\njmp +0\nacc +1").expect("program");
+        machine.push_input(3);
+        let repair = machine.repair().expect("repair");
+        assert_eq!((repair.index, repair.accumulator), (1, 1));
+        assert_eq!(machine.repair_with_graph(), Ok(repair.clone()));
+        assert_eq!(machine.repair_parallel(2), Ok(repair));
+
+        // Without input, swapping the `jmp` only gets as far as blocking.
+        let machine = super::load_machine("jmp +0\nin a +0\nacc +1").expect("program");
+        let expected = machine.repair();
+        assert!(matches!(expected, Err(Halt::InfiniteLoop { .. })));
+        assert_eq!(machine.repair_with_graph(), expected);
+        assert_eq!(machine.repair_parallel(2), expected);
+    }
+
+    #[test]
+    fn repair_needs_a_jmp_or_nop() {
+        let machine = super::load_machine("acc +1\nacc +2").expect("program");
+        assert_eq!(machine.repair(), Err(Halt::Terminated { accumulator: 3 }));
+    }
+
+    pub const PROGRAM: &str = r#"nop +0
 acc +1
 jmp +4
 acc +3
@@ -149,8 +1467,8 @@ jmp -4
 acc +6"#;
     #[test]
     fn parse_it() {
-        let (remainder, instructions) = super::parse::get_instructions(PROGRAM).expect("parse");
-        assert_eq!(remainder, "");
+        let instructions =
+            super::parse::get_instructions(&Registry::default(), PROGRAM).expect("parse");
         assert_eq!(instructions.len(), 9);
     }
 }
@@ -158,43 +1476,237 @@ acc +6"#;
 mod parse {
     use nom::{
         branch::alt,
-        bytes::complete::tag,
-        character::complete::{digit1, newline},
+        bytes::complete::{tag, take_while_m_n},
+        character::complete::digit1,
         combinator::{map, map_res, opt},
-        multi::separated_list0,
-        sequence::separated_pair,
+        error::{Error, ErrorKind},
+        sequence::terminated,
         IResult,
     };
-    fn get_operation_acc(input: &str) -> IResult<&str, super::Operation> {
-        map(tag("acc"), |_| super::Operation::Acc)(input)
-    }
-    fn get_operation_jmp(input: &str) -> IResult<&str, super::Operation> {
-        map(tag("jmp"), |_| super::Operation::Jmp)(input)
-    }
-    fn get_operation_nop(input: &str) -> IResult<&str, super::Operation> {
-        map(tag("nop"), |_| super::Operation::Nop)(input)
-    }
-    fn get_operation(input: &str) -> IResult<&str, super::Operation> {
-        alt((get_operation_acc, get_operation_jmp, get_operation_nop))(input)
-    }
+
+    use std::collections::HashMap;
+
+    use super::{opcode::Registry, Instruction, ParseError};
+
     fn get_sign(input: &str) -> IResult<&str, isize> {
         map(
             opt(alt((map(tag("-"), |_| -1), map(tag("+"), |_| 1)))),
-            |r| if let Some(s) = r { s } else { 1 },
+            |r| r.unwrap_or(1),
         )(input)
     }
-    fn get_isize(input: &str) -> IResult<&str, isize> {
+    pub fn get_isize(input: &str) -> IResult<&str, isize> {
         let (input, sign) = get_sign(input)?;
         let (input, result) = map_res(digit1, |s: &str| s.parse::<isize>())(input)?;
         Ok((input, sign * result))
     }
-    fn get_instruction(input: &str) -> IResult<&str, super::Instruction> {
-        map(
-            separated_pair(get_operation, tag(" "), get_isize),
-            |(op, par)| super::Instruction(op, par),
+    fn get_register(input: &str) -> IResult<&str, super::Register> {
+        map_res(
+            alt((
+                tag("acc"),
+                take_while_m_n(1, 1, |c: char| c.is_ascii_lowercase()),
+            )),
+            |name: &str| name.parse::<super::Register>(),
         )(input)
     }
-    pub fn get_instructions(input: &str) -> IResult<&str, Vec<super::Instruction>> {
-        separated_list0(newline, get_instruction)(input)
+    /// Tries every operation in `registry`, the same as an `alt` over them.
+    pub fn get_instruction<'a>(
+        registry: &Registry,
+        input: &'a str,
+    ) -> IResult<&'a str, Instruction> {
+        for operation in registry.operations() {
+            let name: IResult<&str, &str> = terminated(tag(operation.name()), tag(" "))(input);
+            if let Ok((input, _)) = name {
+                let (input, operation) = match operation.register() {
+                    Some(_) => {
+                        let (input, register) = terminated(get_register, tag(" "))(input)?;
+                        (input, operation.with_register(register))
+                    }
+                    None => (input, operation.clone()),
+                };
+                let (input, argument) = match &operation {
+                    super::Operation::Custom(custom) => custom.0.parse_argument(input)?,
+                    _ => get_isize(input)?,
+                };
+                return Ok((input, Instruction(operation, argument)));
+            }
+        }
+        Err(nom::Err::Error(Error::new(input, ErrorKind::Alt)))
+    }
+    /// A line with an instruction on it.
+    struct Line<'a> {
+        number: usize,
+        text: &'a str,
+        /// Where `instruction` starts in `text`.
+        offset: usize,
+        instruction: &'a str,
+    }
+
+    impl Line<'_> {
+        fn error(&self, column: usize, message: String) -> ParseError {
+            ParseError {
+                line: self.number,
+                column: self.offset + column,
+                text: self.text.to_string(),
+                message,
+            }
+        }
+    }
+
+    /// One instruction per line. Anything after a `;` is a comment, blank
+    /// lines are skipped, and a line may start with a label like `loop:`.
+    /// An argument that names a label is the offset to it.
+    pub fn get_instructions(
+        registry: &Registry,
+        input: &str,
+    ) -> Result<Vec<Instruction>, ParseError> {
+        let mut labels: HashMap<&str, (usize, usize)> = HashMap::new();
+        let mut lines = vec![];
+        for (index, text) in input.lines().enumerate() {
+            let code = text.split(';').next().unwrap_or_default().trim_end();
+            let mut instruction = code.trim_start();
+            if let Some((label, after)) = get_label(instruction) {
+                let line = Line {
+                    number: index + 1,
+                    text,
+                    offset: code.len() - instruction.len(),
+                    instruction,
+                };
+                if let Some((_, defined)) = labels.get(label) {
+                    return Err(line.error(
+                        1,
+                        format!("label `{}` is already defined at line {}", label, defined),
+                    ));
+                }
+                labels.insert(label, (lines.len(), index + 1));
+                instruction = after.trim_start();
+            }
+            if !instruction.is_empty() {
+                lines.push(Line {
+                    number: index + 1,
+                    text,
+                    offset: code.len() - instruction.len(),
+                    instruction,
+                });
+            }
+        }
+        lines
+            .iter()
+            .enumerate()
+            .map(|(index, line)| {
+                let resolved;
+                let instruction = match label_reference(line.instruction) {
+                    Some(start) => {
+                        let name = &line.instruction[start..];
+                        match labels.get(name) {
+                            Some((target, _)) => {
+                                resolved = format!(
+                                    "{}{:+}",
+                                    &line.instruction[..start],
+                                    *target as isize - index as isize
+                                );
+                                &resolved
+                            }
+                            None => {
+                                return Err(
+                                    line.error(start + 1, format!("unknown label `{}`", name))
+                                )
+                            }
+                        }
+                    }
+                    None => line.instruction,
+                };
+                let (column, message) = match get_instruction(registry, instruction) {
+                    Ok(("", instruction)) => return Ok(instruction),
+                    Ok((rest, _)) => (
+                        column(instruction, rest),
+                        format!("unexpected `{}` after the instruction", rest),
+                    ),
+                    Err(_) => diagnose(registry, instruction),
+                };
+                Err(line.error(column, message))
+            })
+            .collect()
+    }
+    fn is_identifier(word: &str) -> bool {
+        let mut chars = word.chars();
+        chars
+            .next()
+            .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+    /// `name:` at the start of `code`, and what follows it.
+    fn get_label(code: &str) -> Option<(&str, &str)> {
+        let colon = code.find(':')?;
+        let name = &code[..colon];
+        if is_identifier(name) {
+            Some((name, &code[colon + 1..]))
+        } else {
+            None
+        }
+    }
+    /// Where the argument starts, when it is a label name.
+    fn label_reference(instruction: &str) -> Option<usize> {
+        let start = instruction.rfind(' ')? + 1;
+        if is_identifier(&instruction[start..]) {
+            Some(start)
+        } else {
+            None
+        }
+    }
+    /// Works out where and why `line` is not an instruction.
+    fn diagnose(registry: &Registry, line: &str) -> (usize, String) {
+        let (name, rest) = match line.find(' ') {
+            Some(space) => (&line[..space], Some(&line[space + 1..])),
+            None => (line, None),
+        };
+        if name.is_empty() {
+            return (1, format!("expected an opcode, found {}", found(line)));
+        }
+        let operation = match registry.get(name) {
+            Some(operation) => operation,
+            None => return (1, format!("unknown opcode `{}`", name)),
+        };
+        let mut rest = match rest {
+            Some(rest) => rest,
+            None => {
+                return (
+                    line.len() + 1,
+                    format!("expected a space and an argument after `{}`", name),
+                )
+            }
+        };
+        if operation.register().is_some() {
+            let register: IResult<&str, _> = terminated(get_register, tag(" "))(rest);
+            match register {
+                Ok((after, _)) => rest = after,
+                Err(_) => {
+                    return (
+                        column(line, rest),
+                        format!(
+                            "expected a register, `acc` or `a` to `z`, and a space, found {}",
+                            found(rest)
+                        ),
+                    )
+                }
+            }
+        }
+        (
+            column(line, rest),
+            format!(
+                "expected an argument like `+3` or `-7` for `{}`, found {}",
+                name,
+                found(rest)
+            ),
+        )
+    }
+    fn column(line: &str, rest: &str) -> usize {
+        line.len() - rest.len() + 1
+    }
+    fn found(rest: &str) -> String {
+        match rest.split(' ').next() {
+            Some(word) if !word.is_empty() => format!("`{}`", word),
+            _ if rest.is_empty() => "the end of the line".to_string(),
+            _ => "a space".to_string(),
+        }
     }
 }
@@ -0,0 +1,352 @@
+use std::convert::TryFrom;
+
+use super::{opcode::Registry, Instruction, Operation, ParseError, Register};
+
+/// What every binary program starts with. No text program starts with a
+/// zero byte.
+pub const MAGIC: &[u8; 4] = b"\0ilg";
+/// The version `encode` writes, and the only one `decode` reads.
+pub const VERSION: u8 = 1;
+
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// The operations every registry has, by their opcode in a binary program.
+/// Operations with a register are listed with `acc`.
+const BUILT_IN: [Operation; 10] = [
+    Operation::Acc,
+    Operation::Jmp,
+    Operation::Nop,
+    Operation::Add(Register::Accumulator),
+    Operation::Load(Register::Accumulator),
+    Operation::Store(Register::Accumulator),
+    Operation::JumpRegister(Register::Accumulator),
+    Operation::In(Register::Accumulator),
+    Operation::Out(Register::Accumulator),
+    Operation::Toggle,
+];
+/// The opcode of the first name in the table of custom opcodes, leaving
+/// room for more built-in ones.
+const FIRST_CUSTOM: u8 = 32;
+
+/// Writes `MAGIC`, `VERSION`, the names of the custom opcodes the program
+/// uses as a varint count followed by each name as a varint length and its
+/// UTF-8 bytes, and then the number of instructions as a varint. Then for
+/// each instruction the opcode as a byte, a register byte for operations
+/// that have one (0 for `acc`, 1 to 26 for `a` to `z`) and the argument as
+/// a zigzag varint. Built-in operations have the opcode of their position in
+/// `BUILT_IN`, custom ones `FIRST_CUSTOM` plus their position in the table.
+/// Fails with the index of the first instruction whose operation is not in
+/// `registry`, or that needs more custom opcodes than there is room for.
+pub fn encode(registry: &Registry, program: &[Instruction]) -> Result<Vec<u8>, usize> {
+    let mut names: Vec<&str> = vec![];
+    let mut opcodes = Vec::with_capacity(program.len());
+    for (index, Instruction(operation, _)) in program.iter().enumerate() {
+        let name = operation.name();
+        if registry.get(name).is_none() {
+            return Err(index);
+        }
+        let opcode = match operation {
+            Operation::Custom(_) => {
+                let position = match names.iter().position(|known| *known == name) {
+                    Some(position) => position,
+                    None => {
+                        names.push(name);
+                        names.len() - 1
+                    }
+                };
+                u8::try_from(position)
+                    .ok()
+                    .and_then(|position| FIRST_CUSTOM.checked_add(position))
+                    .ok_or(index)?
+            }
+            _ => BUILT_IN
+                .iter()
+                .position(|known| known.name() == name)
+                .expect("every built-in operation has an opcode") as u8,
+        };
+        opcodes.push(opcode);
+    }
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    write_varint(&mut bytes, names.len() as u64);
+    for name in names {
+        write_varint(&mut bytes, name.len() as u64);
+        bytes.extend_from_slice(name.as_bytes());
+    }
+    write_varint(&mut bytes, program.len() as u64);
+    for (Instruction(operation, argument), opcode) in program.iter().zip(opcodes) {
+        bytes.push(opcode);
+        match operation.register() {
+            Some(Register::Accumulator) => bytes.push(0),
+            Some(Register::Named(letter)) => bytes.push(letter + 1),
+            None => {}
+        }
+        let argument = *argument as i64;
+        write_varint(&mut bytes, ((argument << 1) ^ (argument >> 63)) as u64);
+    }
+    Ok(bytes)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Reads what `encode` wrote, with a `registry` that has the custom opcodes
+/// named in the header. In errors `line` is the instruction, counting from 1
+/// or 0 for the header, `column` the byte offset counting from 1, and `text`
+/// is empty.
+pub fn decode(registry: &Registry, bytes: &[u8]) -> Result<Vec<Instruction>, ParseError> {
+    let mut reader = Reader {
+        bytes,
+        position: 0,
+        instruction: 0,
+    };
+    if !is_binary(bytes) {
+        return Err(reader.error("not a binary program".to_string()));
+    }
+    reader.position = MAGIC.len();
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(reader.error(format!("unknown version {}", version)));
+    }
+    let mut custom = vec![];
+    for _ in 0..reader.varint()? {
+        let len = reader.varint()? as usize;
+        let name = match bytes.get(reader.position..reader.position.saturating_add(len)) {
+            Some(name) => name,
+            None => {
+                reader.position = bytes.len() + 1;
+                return Err(reader.error("unexpected end of the program".to_string()));
+            }
+        };
+        reader.position += len;
+        let name = String::from_utf8_lossy(name);
+        match registry.get(&name) {
+            Some(operation) => custom.push(operation),
+            None => return Err(reader.error(format!("unknown opcode `{}`", name))),
+        }
+    }
+    let count = reader.varint()?;
+    // Every instruction takes at least two bytes, so a count larger than
+    // that is wrong and must not be trusted with the allocation.
+    let mut program = Vec::with_capacity((count as usize).min(bytes.len() / 2));
+    for instruction in 1..=count {
+        reader.instruction = instruction as usize;
+        let opcode = reader.byte()?;
+        let operation = match opcode.checked_sub(FIRST_CUSTOM) {
+            Some(position) => custom.get(position as usize).copied(),
+            None => BUILT_IN.get(opcode as usize),
+        };
+        let operation = match operation {
+            Some(operation) => operation,
+            None => return Err(reader.error(format!("unknown opcode {}", opcode))),
+        };
+        let operation = match operation.register() {
+            Some(_) => match reader.byte()? {
+                0 => operation.with_register(Register::Accumulator),
+                letter @ 1..=26 => operation.with_register(Register::Named(letter - 1)),
+                other => return Err(reader.error(format!("unknown register {}", other))),
+            },
+            None => operation.clone(),
+        };
+        let zigzag = reader.varint()?;
+        let argument = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+        let argument = match isize::try_from(argument) {
+            Ok(argument) => argument,
+            Err(_) => return Err(reader.error(format!("argument {} is too large", argument))),
+        };
+        program.push(Instruction(operation, argument));
+    }
+    if reader.position < bytes.len() {
+        reader.position += 1;
+        return Err(reader.error("unexpected bytes after the last instruction".to_string()));
+    }
+    Ok(program)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    instruction: usize,
+}
+
+impl Reader<'_> {
+    /// Points at the byte read last.
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            line: self.instruction,
+            column: self.position.max(1),
+            text: String::new(),
+            message,
+        }
+    }
+    fn byte(&mut self) -> Result<u8, ParseError> {
+        match self.bytes.get(self.position) {
+            Some(byte) => {
+                self.position += 1;
+                Ok(*byte)
+            }
+            None => {
+                self.position += 1;
+                Err(self.error("unexpected end of the program".to_string()))
+            }
+        }
+    }
+    fn varint(&mut self) -> Result<u64, ParseError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(self.error("varint longer than 64 bits".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        load_machine, load_machine_with,
+        opcode::{Hlt, Jz, Mul, Registry},
+        tests::random_program,
+        tests::PROGRAM,
+        Instruction, Operation, Program,
+    };
+    use super::{decode, encode};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn round_trip(registry: &Registry, text: &str) {
+        let program: Vec<_> = load_machine_with(registry, text)
+            .expect("program")
+            .program()
+            .to_vec();
+        let bytes = encode(registry, &program).expect("encode");
+        assert_eq!(decode(registry, &bytes).expect("decode"), program);
+        let machine = load_machine_with(registry, &bytes).expect("binary program");
+        assert_eq!(machine.program(), &program[..]);
+    }
+
+    #[test]
+    fn round_trips() {
+        let default = Registry::default();
+        round_trip(&default, PROGRAM);
+        round_trip(&default, "");
+        round_trip(
+            &default,
+            "add z -1\nld acc +2\nin q +0\nacc +9223372036854775807\nnop -9223372036854775807",
+        );
+        let mut registry = Registry::default();
+        registry.register(Mul);
+        round_trip(&registry, "mul +3\nacc -70000");
+        let mut rng = StdRng::seed_from_u64(23);
+        let mut programs = vec![vec![Instruction(Operation::Jmp, isize::MIN)]];
+        programs.extend((0..500).map(|_| random_program(&mut rng)));
+        for program in programs {
+            let bytes = encode(&default, &program).expect("encode");
+            assert_eq!(decode(&default, &bytes).expect("decode"), program);
+        }
+    }
+
+    #[test]
+    fn compact() {
+        let program = load_machine(PROGRAM).expect("program").program().to_vec();
+        let bytes = encode(&Registry::default(), &program).expect("encode");
+        // Only `acc -99` needs a second byte for its argument.
+        assert_eq!(bytes.len(), 7 + 2 * program.len() + 1);
+        assert!(bytes.len() < Program(program).to_string().len() / 2);
+    }
+
+    #[test]
+    fn errors() {
+        let registry = Registry::default();
+        let program = load_machine("acc +1\nadd b -300")
+            .expect("program")
+            .program()
+            .to_vec();
+        let bytes = encode(&registry, &program).expect("encode");
+        let message = |bytes: &[u8]| {
+            let err = decode(&registry, bytes).expect_err("error");
+            (err.line, err.column, err.message)
+        };
+        assert_eq!(
+            message(&bytes[..bytes.len() - 1]),
+            (2, 13, "unexpected end of the program".to_string())
+        );
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(
+            message(&extra),
+            (
+                2,
+                14,
+                "unexpected bytes after the last instruction".to_string()
+            )
+        );
+        let mut version = bytes.clone();
+        version[4] = 2;
+        assert_eq!(message(&version), (0, 5, "unknown version 2".to_string()));
+        let mut opcode = bytes.clone();
+        opcode[9] = 42;
+        assert_eq!(message(&opcode), (2, 10, "unknown opcode 42".to_string()));
+        opcode[9] = 32;
+        assert_eq!(message(&opcode), (2, 10, "unknown opcode 32".to_string()));
+        let mut register = bytes;
+        register[10] = 27;
+        assert_eq!(
+            message(&register),
+            (2, 11, "unknown register 27".to_string())
+        );
+        assert!(
+            message(b"\0ilg\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01")
+                .2
+                .contains("varint")
+        );
+
+        let mut registry = Registry::default();
+        registry.register(Mul);
+        let program = load_machine_with(&registry, "mul +2")
+            .expect("program")
+            .program()
+            .to_vec();
+        assert_eq!(encode(&Registry::default(), &program), Err(0));
+        let bytes = encode(&registry, &program).expect("encode");
+        assert_eq!(message(&bytes), (0, 10, "unknown opcode `mul`".to_string()));
+        assert_eq!(
+            message(&bytes[..8]),
+            (0, 9, "unexpected end of the program".to_string())
+        );
+    }
+
+    #[test]
+    fn opcodes() {
+        let program = load_machine("acc +1\ntgl -1\nout c +0")
+            .expect("program")
+            .program()
+            .to_vec();
+        assert_eq!(
+            encode(&Registry::default(), &program).expect("encode"),
+            b"\0ilg\x01\x00\x03\x00\x02\x09\x01\x08\x03\x00"
+        );
+
+        // Custom opcodes are found by name, wherever they were registered.
+        let mut registry = Registry::default();
+        registry.register(Mul).register(Jz);
+        let program = load_machine_with(&registry, "jz +2\nmul -1\njz +0\nacc +1")
+            .expect("program")
+            .program()
+            .to_vec();
+        let bytes = encode(&registry, &program).expect("encode");
+        assert!(bytes.starts_with(b"\0ilg\x01\x02\x02jz\x03mul\x04\x20\x04\x21\x01\x20"));
+        let mut other = Registry::default();
+        other.register(Jz).register(Hlt).register(Mul);
+        assert_eq!(decode(&other, &bytes).expect("decode"), program);
+    }
+}
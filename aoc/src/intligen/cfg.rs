@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+
+use super::{opcode::Flow, Instruction};
+
+/// Static control flow of a program. Node `i` is the instruction at index
+/// `i`, and the extra node `exit` stands for the position just past the last
+/// instruction, where the program terminates. Instructions with several
+/// possible flows, like a conditional jump, get an edge for each.
+#[derive(Debug, Clone)]
+pub struct ControlFlowGraph {
+    successors: Vec<Vec<usize>>,
+    reachable_from_entry: HashSet<usize>,
+    reaches_exit: HashSet<usize>,
+}
+
+/// Where `flow` from the instruction at `index` continues, `None` when it
+/// leaves the program through anything other than the exit.
+pub fn target(index: usize, flow: Flow, len: usize) -> Option<usize> {
+    let target = match flow {
+        Flow::Next => index as isize + 1,
+        Flow::Jump(offset) => (index as isize).saturating_add(offset),
+        Flow::Halt => len as isize,
+        Flow::Fault(_) | Flow::Indirect => return None,
+    };
+    if target < 0 || target as usize > len {
+        None
+    } else {
+        Some(target as usize)
+    }
+}
+
+/// Every node `instruction` at `index` may continue at. An indirect jump
+/// may continue anywhere.
+pub fn successors(index: usize, instruction: &Instruction, len: usize) -> Vec<usize> {
+    let mut successors = vec![];
+    for flow in instruction.0.flows(instruction.1) {
+        match flow {
+            Flow::Indirect => return (0..=len).collect(),
+            flow => successors.extend(target(index, flow, len)),
+        }
+    }
+    successors
+}
+
+impl ControlFlowGraph {
+    pub fn new(program: &[Instruction]) -> ControlFlowGraph {
+        let exit = program.len();
+        let mut successors: Vec<Vec<usize>> = program
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| successors(index, instruction, exit))
+            .collect();
+        successors.push(vec![]);
+
+        let mut predecessors: Vec<Vec<usize>> = vec![vec![]; successors.len()];
+        for (node, targets) in successors.iter().enumerate() {
+            for target in targets {
+                predecessors[*target].push(node);
+            }
+        }
+
+        ControlFlowGraph {
+            reachable_from_entry: reachable(&successors, 0),
+            reaches_exit: reachable(&predecessors, exit),
+            successors,
+        }
+    }
+    /// All nodes, including `exit`.
+    pub fn nodes(&self) -> std::ops::Range<usize> {
+        0..self.successors.len()
+    }
+    pub fn exit(&self) -> usize {
+        self.successors.len() - 1
+    }
+    pub fn successors(&self, node: usize) -> &[usize] {
+        &self.successors[node]
+    }
+    /// Every edge as `(from, to)`.
+    pub fn edges(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.successors
+            .iter()
+            .enumerate()
+            .flat_map(|(from, targets)| targets.iter().map(move |to| (from, *to)))
+    }
+    /// Nodes the program can get to when started at instruction 0.
+    pub fn reachable_from_entry(&self) -> &HashSet<usize> {
+        &self.reachable_from_entry
+    }
+    /// Nodes from which the program runs to `exit`.
+    pub fn reaches_exit(&self) -> &HashSet<usize> {
+        &self.reaches_exit
+    }
+    /// The lowest index whose `jmp`/`nop` swap makes a non-terminating
+    /// program terminate. A swap only matters on the path from the entry, and
+    /// it works exactly when the swapped successor already reaches the exit.
+    /// That reasoning needs a single path, so this is only exact for programs
+    /// where every instruction has one flow.
+    pub fn repair_index(&self, program: &[Instruction]) -> Option<usize> {
+        if self.reaches_exit.contains(&0) {
+            return None;
+        }
+        let exit = self.exit();
+        let mut candidates: Vec<usize> = self
+            .reachable_from_entry
+            .iter()
+            .cloned()
+            .filter(|index| *index != exit)
+            .collect();
+        candidates.sort_unstable();
+        candidates.into_iter().find(|index| {
+            program[*index]
+                .toggled()
+                .and_then(|toggled| successors(*index, &toggled, exit).pop())
+                .is_some_and(|target| self.reaches_exit.contains(&target))
+        })
+    }
+}
+
+fn reachable(edges: &[Vec<usize>], start: usize) -> HashSet<usize> {
+    let mut seen: HashSet<usize> = HashSet::default();
+    let mut pending = vec![start];
+    while let Some(node) = pending.pop() {
+        if seen.insert(node) {
+            pending.extend(edges[node].iter().filter(|next| !seen.contains(next)));
+        }
+    }
+    seen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        load_machine_with,
+        opcode::{Hlt, Jz, Registry},
+        tests::random_program,
+        Halt, Machine,
+    };
+    use super::ControlFlowGraph;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn sample() {
+        let machine = super::super::load_machine(super::super::tests::PROGRAM).expect("program");
+        let graph = ControlFlowGraph::new(machine.program());
+        assert_eq!(graph.exit(), 9);
+        assert_eq!(graph.successors(2), &[6]);
+        assert!(!graph.reaches_exit().contains(&0));
+        assert!(graph.reaches_exit().contains(&8));
+        assert_eq!(graph.repair_index(machine.program()), Some(7));
+    }
+
+    #[test]
+    fn custom_flows() {
+        let mut registry = Registry::default();
+        registry.register(Jz).register(Hlt);
+        let machine = load_machine_with(&registry, "jz +2\nhlt +0\njmp -2").expect("program");
+        let graph = ControlFlowGraph::new(machine.program());
+        assert_eq!(graph.successors(0), &[1, 2]);
+        assert_eq!(graph.successors(1), &[3]);
+        assert!(graph.reaches_exit().contains(&2));
+
+        let machine = super::super::load_machine("jr a +1\nacc +1").expect("program");
+        let graph = ControlFlowGraph::new(machine.program());
+        assert_eq!(graph.successors(0), &[0, 1, 2]);
+    }
+
+    #[test]
+    fn branches_left_to_brute_force() {
+        let mut registry = Registry::default();
+        registry.register(Jz);
+        let machine =
+            load_machine_with(&registry, "acc +1\njz +2\njmp +0\nacc +1").expect("program");
+        let repair = machine.repair().expect("repair");
+        assert_eq!(repair.index, 2);
+        assert_eq!(machine.repair_with_graph(), Ok(repair));
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..500 {
+            let program = random_program(&mut rng);
+            let graph = ControlFlowGraph::new(&program);
+
+            let mut machine = Machine::new(program.clone());
+            let halt = machine.run_to_first();
+            let mut executed: Vec<usize> = machine.path.clone();
+            if halt.is_terminated() {
+                executed.push(graph.exit());
+            }
+            executed.sort_unstable();
+            let mut reachable: Vec<usize> = graph.reachable_from_entry().iter().cloned().collect();
+            reachable.sort_unstable();
+            assert_eq!(executed, reachable, "{:?}", program);
+
+            for start in graph.nodes() {
+                let mut machine = Machine::new(program.clone());
+                machine.program_counter = start;
+                let terminates = matches!(machine.run_to_first(), Halt::Terminated { .. });
+                assert_eq!(graph.reaches_exit().contains(&start), terminates);
+            }
+
+            assert_eq!(
+                Machine::new(program.clone()).repair(),
+                Machine::new(program.clone()).repair_with_graph(),
+                "{:?}",
+                program
+            );
+        }
+
+        // The graph has no overflow, so it swaps the `nop` and runs into it.
+        let machine =
+            super::super::load_machine("acc +9223372036854775807\nnop +3\njmp +0\njmp +2\nacc +1")
+                .expect("program");
+        let repair = machine.repair().expect("repair");
+        assert_eq!(repair.index, 2);
+        assert_eq!(machine.repair_with_graph(), Ok(repair));
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+
+use super::{Instruction, Machine};
+
+/// Hit counts for every instruction of a program after a run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    program: Vec<Instruction>,
+    hits: Vec<usize>,
+}
+
+impl Coverage {
+    pub fn of(machine: &Machine) -> Coverage {
+        Coverage {
+            program: machine.program().to_vec(),
+            hits: machine.hits().to_vec(),
+        }
+    }
+    pub fn hits(&self) -> &[usize] {
+        &self.hits
+    }
+    /// Indices of the instructions that never ran.
+    pub fn never_run(&self) -> Vec<usize> {
+        self.hits
+            .iter()
+            .enumerate()
+            .filter(|(_, hits)| **hits == 0)
+            .map(|(index, _)| index)
+            .collect()
+    }
+    pub fn covered(&self) -> usize {
+        self.hits.iter().filter(|hits| **hits > 0).count()
+    }
+}
+
+/// An annotated listing with the hit count in front of every line, and
+/// `#####` for lines that never ran.
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (instruction, hits)) in self.program.iter().zip(&self.hits).enumerate() {
+            if *hits == 0 {
+                writeln!(f, "{:>8}  {:>4}: {}", "#####", index, instruction)?;
+            } else {
+                writeln!(f, "{:>8}  {:>4}: {}", hits, index, instruction)?;
+            }
+        }
+        write!(
+            f,
+            "covered {} of {} instructions",
+            self.covered(),
+            self.program.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{load_machine, tests::PROGRAM};
+    use super::Coverage;
+
+    #[test]
+    fn sample() {
+        let mut machine = load_machine(PROGRAM).expect("program");
+        machine.run_to_first();
+        let coverage = Coverage::of(&machine);
+        assert_eq!(coverage.hits(), &[1, 1, 1, 1, 1, 0, 1, 1, 0]);
+        assert_eq!(coverage.never_run(), vec![5, 8]);
+        assert_eq!(
+            coverage.to_string(),
+            "       1     0: nop +0\n       \
+                    1     1: acc +1\n       \
+                    1     2: jmp +4\n       \
+                    1     3: acc +3\n       \
+                    1     4: jmp -3\n   \
+                #####     5: acc -99\n       \
+                    1     6: acc +1\n       \
+                    1     7: jmp -4\n   \
+                #####     8: acc +6\n\
+             covered 7 of 9 instructions"
+        );
+    }
+
+    #[test]
+    fn counts_repeats() {
+        let mut machine = load_machine(PROGRAM).expect("program").with_history(10);
+        for _ in 0..9 {
+            machine.step();
+        }
+        assert_eq!(machine.hits()[1], 2);
+        machine.step_back();
+        machine.step_back();
+        assert_eq!(machine.hits()[1], 1);
+    }
+}
@@ -0,0 +1,241 @@
+use super::{Halt, Instruction, Machine, State};
+
+/// Everything that decides what a machine does next.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Configuration {
+    pub program_counter: usize,
+    pub state: State,
+    pub program: Vec<Instruction>,
+}
+
+impl Configuration {
+    pub fn of(machine: &Machine) -> Configuration {
+        Configuration {
+            program_counter: machine.program_counter,
+            state: machine.state.clone(),
+            program: machine.program.clone(),
+        }
+    }
+}
+
+/// After `entry_step` steps the machine repeats the same `length` steps
+/// forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cycle {
+    pub entry_step: usize,
+    pub length: usize,
+}
+
+impl Cycle {
+    /// The smallest number of steps that ends in the same configuration as
+    /// running `steps`.
+    pub fn reduce(&self, steps: usize) -> usize {
+        if steps <= self.entry_step {
+            steps
+        } else {
+            self.entry_step + (steps - self.entry_step) % self.length
+        }
+    }
+}
+
+/// What `Machine::search_cycle` got to.
+enum Search {
+    Found(Cycle),
+    /// Where the machine got to after running as many steps as it was
+    /// allowed, without repeating itself.
+    GaveUp(Box<Configuration>),
+}
+
+impl Machine {
+    /// Finds where running from the current configuration starts to repeat
+    /// itself, with Brent's algorithm, or returns how the machine halts
+    /// first. Only an exact repeat of the program counter, state and program
+    /// counts, so a loop that keeps changing the accumulator never ends
+    /// unless the machine has fuel or a deadline.
+    pub fn find_cycle(&self) -> Result<Cycle, Halt> {
+        match self.search_cycle(usize::MAX)? {
+            Search::Found(cycle) => Ok(cycle),
+            Search::GaveUp(_) => unreachable!("nothing runs usize::MAX steps"),
+        }
+    }
+    /// `find_cycle`, giving up once the machine searching ahead has run
+    /// `limit` steps.
+    fn search_cycle(&self, limit: usize) -> Result<Search, Halt> {
+        let mut power = 1;
+        let mut length = 1;
+        let mut tortoise = self.detached();
+        let mut hare = self.detached();
+        if limit == 0 {
+            return Ok(Search::GaveUp(Box::new(Configuration::of(&hare))));
+        }
+        advance(&mut hare)?;
+        let mut ahead = 1;
+        while !same_configuration(&tortoise, &hare) {
+            if ahead == limit {
+                return Ok(Search::GaveUp(Box::new(Configuration::of(&hare))));
+            }
+            if power == length {
+                tortoise = hare.detached();
+                power *= 2;
+                length = 0;
+            }
+            advance(&mut hare)?;
+            ahead += 1;
+            length += 1;
+        }
+
+        let mut tortoise = self.detached();
+        let mut hare = self.detached();
+        for _ in 0..length {
+            advance(&mut hare)?;
+        }
+        let mut entry_step = 0;
+        while !same_configuration(&tortoise, &hare) {
+            advance(&mut tortoise)?;
+            advance(&mut hare)?;
+            entry_step += 1;
+        }
+        Ok(Search::Found(Cycle { entry_step, length }))
+    }
+    /// The configuration after running `steps` more steps. Looking for a
+    /// cycle stops after `steps`, and once one is found only enough steps to
+    /// go round it once are run.
+    pub fn predict(&self, steps: usize) -> Result<Configuration, Halt> {
+        let steps = match self.search_cycle(steps)? {
+            Search::Found(cycle) => cycle.reduce(steps),
+            Search::GaveUp(configuration) => return Ok(*configuration),
+        };
+        let mut machine = self.detached();
+        for _ in 0..steps {
+            advance(&mut machine)?;
+        }
+        Ok(Configuration::of(&machine))
+    }
+    /// A machine in the same configuration, under the same limits, that
+    /// leaves finding loops to the caller.
+    fn detached(&self) -> Machine {
+        Machine {
+            state: self.state.clone(),
+            program_counter: self.program_counter,
+            ..self.with_program(self.program.clone())
+        }
+        .without_loop_detection()
+    }
+}
+
+fn advance(machine: &mut Machine) -> Result<(), Halt> {
+    match machine.step() {
+        Some(halt) => Err(halt),
+        None => Ok(()),
+    }
+}
+
+fn same_configuration(left: &Machine, right: &Machine) -> bool {
+    left.program_counter == right.program_counter
+        && left.state == right.state
+        && left.program == right.program
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        load_machine, load_machine_with,
+        opcode::{Jz, Registry},
+        tests::PROGRAM,
+        Halt,
+    };
+    use super::{Configuration, Cycle};
+
+    const CYCLE: &str = "acc +1\nnop +0\nacc -1\nacc +1\njmp -3";
+
+    #[test]
+    fn finds_cycle() {
+        let machine = load_machine(CYCLE).expect("program");
+        assert_eq!(
+            machine.find_cycle(),
+            Ok(Cycle {
+                entry_step: 1,
+                length: 4
+            })
+        );
+        let machine = load_machine("tgl +1\nnop +1\njmp -2").expect("program");
+        assert_eq!(
+            machine.find_cycle(),
+            Ok(Cycle {
+                entry_step: 0,
+                length: 6
+            })
+        );
+    }
+
+    #[test]
+    fn no_cycle() {
+        let machine = load_machine("acc +1\njmp +1").expect("program");
+        assert_eq!(
+            machine.find_cycle(),
+            Err(Halt::Terminated { accumulator: 1 })
+        );
+        // The accumulator grows on every round, so nothing ever repeats.
+        let machine = load_machine(PROGRAM).expect("program").with_fuel(1000);
+        assert!(matches!(machine.find_cycle(), Err(Halt::OutOfFuel { .. })));
+    }
+
+    #[test]
+    fn predict() {
+        let machine = load_machine(CYCLE).expect("program");
+        let mut running = load_machine(CYCLE)
+            .expect("program")
+            .without_loop_detection();
+        for steps in 0..40 {
+            assert_eq!(machine.predict(steps), Ok(Configuration::of(&running)));
+            running.step();
+        }
+        let far = machine.predict(1_000_000_001).expect("configuration");
+        assert_eq!(far.program_counter, 1);
+        assert_eq!(far.state.accumulator, 1);
+        assert_eq!(
+            load_machine("acc +1\njmp +1").expect("program").predict(5),
+            Err(Halt::Terminated { accumulator: 1 })
+        );
+    }
+
+    #[test]
+    fn predict_without_cycle() {
+        // Nothing repeats and there is no fuel, so only `steps` may be run.
+        let machine = load_machine(PROGRAM).expect("program");
+        let mut running = load_machine(PROGRAM)
+            .expect("program")
+            .without_loop_detection();
+        for steps in 0..20 {
+            assert_eq!(machine.predict(steps), Ok(Configuration::of(&running)));
+            running.step();
+        }
+        for _ in 20..100_000 {
+            running.step();
+        }
+        assert_eq!(machine.predict(100_000), Ok(Configuration::of(&running)));
+
+        let mut detached = machine.detached();
+        for _ in 0..1000 {
+            detached.step();
+        }
+        assert!(detached.path.is_empty() && detached.visited.is_empty());
+    }
+
+    #[test]
+    fn branch_on_state() {
+        let mut registry = Registry::default();
+        registry.register(Jz);
+        let program = "acc +3\nacc -1\njz +2\njmp -2\nnop +0";
+        let mut machine = load_machine_with(&registry, program).expect("program");
+        assert_eq!(machine.run_to_first(), Halt::Terminated { accumulator: 0 });
+        let machine = load_machine_with(&registry, "acc +1\njz +2\njmp -1").expect("program");
+        assert_eq!(
+            machine.find_cycle(),
+            Ok(Cycle {
+                entry_step: 1,
+                length: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use super::{snapshot::Snapshot, Halt, Instruction, Machine};
+
+const HELP: &str = "commands:
+  break <pc>              stop when execution reaches <pc>
+  delete <pc>             remove the breakpoint at <pc>
+  step [n]                run n instructions (default 1)
+  back [n]                undo n instructions (default 1)
+  continue                run until a breakpoint, watch or halt
+  watch [value]           stop when the accumulator changes, or equals value
+  unwatch                 remove the accumulator watch
+  list [n]                show n instructions around the program counter
+  patch <pc> <op> <arg>   replace the instruction at <pc>
+  print                   show program counter, accumulator and step count
+  save <file>             write the machine to <file>, see `Snapshot`
+  load <file>             carry on from a machine saved with `save`
+  quit";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Break(usize),
+    Delete(usize),
+    Step(usize),
+    Back(usize),
+    Continue,
+    Watch(Option<isize>),
+    Unwatch,
+    List(usize),
+    Patch(usize, Instruction),
+    Print,
+    Save(String),
+    Load(String),
+    Help,
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = String;
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        fn number<T: FromStr>(word: Option<&str>) -> Result<T, String> {
+            let word = word.ok_or_else(|| "missing argument".to_string())?;
+            word.parse().map_err(|_| format!("not a number: {}", word))
+        }
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let command = match command {
+            "b" | "break" => Command::Break(number(words.next())?),
+            "d" | "delete" => Command::Delete(number(words.next())?),
+            "s" | "step" => match words.next() {
+                Some(count) => Command::Step(number(Some(count))?),
+                None => Command::Step(1),
+            },
+            "back" => match words.next() {
+                Some(count) => Command::Back(number(Some(count))?),
+                None => Command::Back(1),
+            },
+            "c" | "continue" => Command::Continue,
+            "w" | "watch" => match words.next() {
+                Some(value) => Command::Watch(Some(number(Some(value))?)),
+                None => Command::Watch(None),
+            },
+            "unwatch" => Command::Unwatch,
+            "l" | "list" => match words.next() {
+                Some(count) => Command::List(number(Some(count))?),
+                None => Command::List(3),
+            },
+            "patch" => {
+                let index = number(words.next())?;
+                let instruction = words.collect::<Vec<_>>().join(" ").parse()?;
+                return Ok(Command::Patch(index, instruction));
+            }
+            "p" | "print" => Command::Print,
+            "save" | "load" => {
+                let path = words.next().ok_or_else(|| "missing file".to_string())?;
+                if command == "save" {
+                    Command::Save(path.to_string())
+                } else {
+                    Command::Load(path.to_string())
+                }
+            }
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            _ => return Err(format!("unknown command: {}", command)),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("unexpected argument: {}", extra)),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Runs a `Machine` under interactive control, reading one command per line.
+pub struct Debugger {
+    machine: Machine,
+    breakpoints: BTreeSet<usize>,
+    watch: Option<Option<isize>>,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine) -> Debugger {
+        Debugger {
+            machine,
+            breakpoints: BTreeSet::default(),
+            watch: None,
+        }
+    }
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+    /// Reads commands from `input` until `quit` or the end of input.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.parse() {
+                Ok(Command::Quit) => break,
+                Ok(command) => self.execute(command, &mut output)?,
+                Err(err) => writeln!(output, "error: {}", err)?,
+            }
+        }
+        Ok(())
+    }
+    pub fn execute<W: Write>(&mut self, command: Command, output: &mut W) -> io::Result<()> {
+        match command {
+            Command::Break(index) => {
+                self.breakpoints.insert(index);
+                writeln!(output, "breakpoint at {}", index)
+            }
+            Command::Delete(index) => {
+                if self.breakpoints.remove(&index) {
+                    writeln!(output, "deleted breakpoint at {}", index)
+                } else {
+                    writeln!(output, "no breakpoint at {}", index)
+                }
+            }
+            Command::Step(count) => {
+                for _ in 0..count {
+                    if let Some(halt) = self.machine.step() {
+                        return self.halted(&halt, output);
+                    }
+                }
+                self.location(output)
+            }
+            Command::Back(count) => {
+                for _ in 0..count {
+                    if !self.machine.step_back() {
+                        writeln!(output, "no more history")?;
+                        break;
+                    }
+                }
+                self.location(output)
+            }
+            Command::Continue => self.resume(output),
+            Command::Watch(value) => {
+                self.watch = Some(value);
+                match value {
+                    Some(value) => writeln!(output, "watching for accumulator {}", value),
+                    None => writeln!(output, "watching accumulator changes"),
+                }
+            }
+            Command::Unwatch => {
+                self.watch = None;
+                writeln!(output, "not watching")
+            }
+            Command::List(context) => self.list(context, output),
+            Command::Patch(index, instruction) => {
+                let text = instruction.to_string();
+                match self.machine.patch(index, instruction) {
+                    Some(old) => writeln!(output, "{}: {} -> {}", index, old, text),
+                    None => writeln!(output, "error: no instruction at {}", index),
+                }
+            }
+            Command::Print => writeln!(
+                output,
+                "pc {} acc {} steps {}",
+                self.machine.program_counter(),
+                self.machine.accumulator(),
+                self.machine.steps()
+            ),
+            Command::Save(path) => {
+                match std::fs::write(&path, self.machine.snapshot().to_string()) {
+                    Ok(()) => writeln!(output, "saved to {}", path),
+                    Err(err) => writeln!(output, "error: {}", err),
+                }
+            }
+            Command::Load(path) => {
+                let snapshot = std::fs::read_to_string(&path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|text| text.parse::<Snapshot>());
+                match snapshot {
+                    Ok(snapshot) => {
+                        self.machine.restore(&snapshot);
+                        self.location(output)
+                    }
+                    Err(err) => writeln!(output, "error: {}", err),
+                }
+            }
+            Command::Help => writeln!(output, "{}", HELP),
+            Command::Quit => Ok(()),
+        }
+    }
+    fn resume<W: Write>(&mut self, output: &mut W) -> io::Result<()> {
+        loop {
+            let before = self.machine.accumulator();
+            if let Some(halt) = self.machine.step() {
+                return self.halted(&halt, output);
+            }
+            let after = self.machine.accumulator();
+            let watched = match self.watch {
+                Some(Some(value)) => after == value && before != value,
+                Some(None) => after != before,
+                None => false,
+            };
+            if watched {
+                writeln!(output, "watch: accumulator {} -> {}", before, after)?;
+                return self.location(output);
+            }
+            if self.breakpoints.contains(&self.machine.program_counter()) {
+                writeln!(output, "breakpoint at {}", self.machine.program_counter())?;
+                return self.location(output);
+            }
+        }
+    }
+    fn halted<W: Write>(&self, halt: &Halt, output: &mut W) -> io::Result<()> {
+        writeln!(output, "halted: {:?}", halt)
+    }
+    fn location<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let pc = self.machine.program_counter();
+        match self.machine.program().get(pc) {
+            Some(instruction) => writeln!(
+                output,
+                "{}: {}  acc {}",
+                pc,
+                instruction,
+                self.machine.accumulator()
+            ),
+            None => writeln!(output, "{}: end  acc {}", pc, self.machine.accumulator()),
+        }
+    }
+    fn list<W: Write>(&self, context: usize, output: &mut W) -> io::Result<()> {
+        let pc = self.machine.program_counter();
+        let program = self.machine.program();
+        let end = (pc + context + 1).min(program.len());
+        for (index, instruction) in program
+            .iter()
+            .enumerate()
+            .take(end)
+            .skip(pc.saturating_sub(context))
+        {
+            let marker = if index == pc { '>' } else { ' ' };
+            let breakpoint = if self.breakpoints.contains(&index) {
+                '*'
+            } else {
+                ' '
+            };
+            writeln!(
+                output,
+                "{}{} {:>4}: {}",
+                marker, breakpoint, index, instruction
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Debugger};
+    use crate::intligen::{load_machine, tests::PROGRAM, Instruction, Operation};
+
+    fn session(script: &str) -> String {
+        let mut debugger = Debugger::new(load_machine(PROGRAM).expect("program").with_history(4));
+        let mut output = vec![];
+        debugger
+            .run(script.as_bytes(), &mut output)
+            .expect("session");
+        String::from_utf8(output).expect("utf8")
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!("step".parse(), Ok(Command::Step(1)));
+        assert_eq!("s 4".parse(), Ok(Command::Step(4)));
+        assert_eq!("back".parse(), Ok(Command::Back(1)));
+        assert_eq!("watch -2".parse(), Ok(Command::Watch(Some(-2))));
+        assert_eq!(
+            "patch 7 nop -4".parse(),
+            Ok(Command::Patch(7, Instruction(Operation::Nop, -4)))
+        );
+        assert_eq!("save a.txt".parse(), Ok(Command::Save("a.txt".to_string())));
+        assert!("load".parse::<Command>().is_err());
+        assert!("break".parse::<Command>().is_err());
+        assert!("jump 3".parse::<Command>().is_err());
+        assert!("step 1 2".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn breakpoints_and_steps() {
+        let output = session("break 6\ncontinue\nstep 2\nprint\nlist 1\n");
+        assert_eq!(
+            output,
+            "breakpoint at 6\n\
+             breakpoint at 6\n\
+             6: acc +1  acc 1\n\
+             3: acc +3  acc 2\n\
+             pc 3 acc 2 steps 5\n\
+             \x20     2: jmp +4\n\
+             >     3: acc +3\n\
+             \x20     4: jmp -3\n"
+        );
+    }
+
+    #[test]
+    fn reverse_steps() {
+        let output = session("step 6\nback 2\nprint\nback 5\nprint\n");
+        assert_eq!(
+            output,
+            "4: jmp -3  acc 5\n\
+             7: jmp -4  acc 2\n\
+             pc 7 acc 2 steps 4\n\
+             no more history\n\
+             2: jmp +4  acc 1\n\
+             pc 2 acc 1 steps 2\n"
+        );
+    }
+
+    #[test]
+    fn watches() {
+        let output = session("watch\ncontinue\nwatch 5\ncontinue\ncontinue\n");
+        assert_eq!(
+            output,
+            "watching accumulator changes\n\
+             watch: accumulator 0 -> 1\n\
+             2: jmp +4  acc 1\n\
+             watching for accumulator 5\n\
+             watch: accumulator 2 -> 5\n\
+             4: jmp -3  acc 5\n\
+             halted: InfiniteLoop { entry: 1, cycle: [1, 2, 6, 7, 3, 4], accumulator: 5 }\n"
+        );
+    }
+
+    #[test]
+    fn patch_and_finish() {
+        let output = session("patch 7 nop -4\npatch 12 nop +0\ncontinue\nquit\nprint\n");
+        assert_eq!(
+            output,
+            "7: jmp -4 -> nop -4\n\
+             error: no instruction at 12\n\
+             halted: Terminated { accumulator: 8 }\n"
+        );
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("intligen-{}.snapshot", std::process::id()));
+        let path = path.to_str().expect("path");
+        let output = session(&format!(
+            "step 3\nsave {0}\ncontinue\nload {0}\nprint\nload {0}.missing\n",
+            path
+        ));
+        std::fs::remove_file(path).expect("remove");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "6: acc +1  acc 1");
+        assert_eq!(lines[1], format!("saved to {}", path));
+        assert_eq!(lines[3], "6: acc +1  acc 1");
+        assert_eq!(lines[4], "pc 6 acc 1 steps 3");
+        assert!(lines[5].starts_with("error: "));
+    }
+}
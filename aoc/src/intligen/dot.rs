@@ -0,0 +1,117 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use super::{cfg, opcode::Flow, Halt, Instruction, Machine};
+
+/// Renders `program` as a Graphviz digraph. Fall-through edges are solid,
+/// jumps are dashed, and the instructions in `highlight` are filled in.
+/// Opcodes that halt get an edge to the exit, and register jumps a dotted
+/// edge to a single `indirect` node.
+pub fn render(program: &[Instruction], highlight: &HashSet<usize>) -> String {
+    let exit = program.len();
+    let mut dot = String::new();
+    writeln!(dot, "digraph program {{").unwrap();
+    writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+    for (index, instruction) in program.iter().enumerate() {
+        let style = if highlight.contains(&index) {
+            ", style=filled, fillcolor=lightcoral"
+        } else {
+            ""
+        };
+        writeln!(
+            dot,
+            "    n{} [label=\"{}: {}\"{}];",
+            index, index, instruction, style
+        )
+        .unwrap();
+    }
+    writeln!(dot, "    n{} [label=\"exit\", shape=doublecircle];", exit).unwrap();
+    let mut out_of_bounds = false;
+    let mut indirect = false;
+    for (index, instruction) in program.iter().enumerate() {
+        for flow in instruction.0.flows(instruction.1) {
+            let target = match (flow, cfg::target(index, flow, exit)) {
+                (Flow::Indirect, _) => {
+                    indirect = true;
+                    "indirect".to_string()
+                }
+                (_, Some(target)) => format!("n{}", target),
+                (_, None) => {
+                    out_of_bounds = true;
+                    "out_of_bounds".to_string()
+                }
+            };
+            let mut attributes = vec![];
+            match flow {
+                Flow::Jump(_) => attributes.push("style=dashed"),
+                Flow::Indirect => attributes.push("style=dotted"),
+                _ => (),
+            }
+            if highlight.contains(&index) {
+                attributes.push("color=red");
+            }
+            if attributes.is_empty() {
+                writeln!(dot, "    n{} -> {};", index, target).unwrap();
+            } else {
+                writeln!(
+                    dot,
+                    "    n{} -> {} [{}];",
+                    index,
+                    target,
+                    attributes.join(", ")
+                )
+                .unwrap();
+            }
+        }
+    }
+    if out_of_bounds {
+        writeln!(
+            dot,
+            "    out_of_bounds [label=\"out of bounds\", shape=octagon];"
+        )
+        .unwrap();
+    }
+    if indirect {
+        writeln!(
+            dot,
+            "    indirect [label=\"register jump\", shape=diamond];"
+        )
+        .unwrap();
+    }
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+/// Renders `program` with the loop that `run_to_first` ends in highlighted.
+pub fn render_with_loop(program: &[Instruction]) -> String {
+    let highlight = match Machine::new(program.to_vec()).run_to_first() {
+        Halt::InfiniteLoop { cycle, .. } => cycle.into_iter().collect(),
+        _ => HashSet::default(),
+    };
+    render(program, &highlight)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn sample() {
+        let machine = super::super::load_machine(super::super::tests::PROGRAM).expect("program");
+        let dot = super::render_with_loop(machine.program());
+        assert!(dot.starts_with("digraph program {\n"));
+        assert!(dot.contains("    n0 [label=\"0: nop +0\"];\n"));
+        assert!(dot.contains("    n1 [label=\"1: acc +1\", style=filled, fillcolor=lightcoral];\n"));
+        assert!(dot.contains("    n2 -> n6 [style=dashed, color=red];\n"));
+        assert!(dot.contains("    n5 -> n6;\n"));
+        assert!(dot.contains("    n8 -> n9;\n"));
+        assert!(dot.contains("    n9 [label=\"exit\", shape=doublecircle];\n"));
+        assert!(!dot.contains("out_of_bounds"));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let machine = super::super::load_machine("acc +2\njmp -2").expect("program");
+        let dot = super::render_with_loop(machine.program());
+        assert!(dot.contains("    n1 -> out_of_bounds [style=dashed];\n"));
+        assert!(dot.contains("    out_of_bounds [label=\"out of bounds\", shape=octagon];\n"));
+    }
+}
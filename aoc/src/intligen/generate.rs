@@ -0,0 +1,267 @@
+use std::ops::Range;
+
+use rand::Rng;
+
+use super::{
+    bytecode::Bytecode,
+    opcode::{Flow, Registry},
+    optimize::optimize,
+    Halt, Instruction, Machine, Operation, Register,
+};
+
+/// Makes random programs, of `acc`, `jmp` and `nop` unless given other
+/// operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Generator {
+    length: Range<usize>,
+    /// The operations to pick from and how often, relative to each other.
+    mix: Vec<(Operation, u32)>,
+    argument_range: isize,
+    jump_range: Option<isize>,
+    terminating: bool,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Generator {
+            length: 1..30,
+            mix: vec![
+                (Operation::Acc, 1),
+                (Operation::Jmp, 1),
+                (Operation::Nop, 1),
+            ],
+            argument_range: 100,
+            jump_range: None,
+            terminating: false,
+        }
+    }
+}
+
+impl Generator {
+    pub fn with_length(mut self, length: Range<usize>) -> Generator {
+        self.length = length;
+        self
+    }
+    /// How often each of `acc`, `jmp` and `nop` is picked, relative to the
+    /// others. At least one weight in the mix must not be zero.
+    pub fn with_mix(self, acc: u32, jmp: u32, nop: u32) -> Generator {
+        self.with_weight(Operation::Acc, acc)
+            .with_weight(Operation::Jmp, jmp)
+            .with_weight(Operation::Nop, nop)
+    }
+    /// How often `operation` is picked, relative to the others, adding it to
+    /// the mix if it is not there yet. Operations are told apart by name, and
+    /// those with a register get `acc` or one of `a` to `c` at random.
+    pub fn with_weight(mut self, operation: Operation, weight: u32) -> Generator {
+        match self
+            .mix
+            .iter_mut()
+            .find(|(known, _)| known.name() == operation.name())
+        {
+            Some(known) => known.1 = weight,
+            None => self.mix.push((operation, weight)),
+        }
+        self
+    }
+    /// Picks from every operation in `registry`, custom ones included, all
+    /// equally often.
+    pub fn with_registry(mut self, registry: &Registry) -> Generator {
+        self.mix = registry
+            .operations()
+            .iter()
+            .map(|operation| (operation.clone(), 1))
+            .collect();
+        self
+    }
+    /// Arguments of anything but jumps and `tgl` are at most `range` away
+    /// from zero.
+    pub fn with_argument_range(mut self, range: isize) -> Generator {
+        self.argument_range = range;
+        self
+    }
+    /// Jumps go at most `range` instructions either way. Without it they may
+    /// go as far as the length of the program.
+    pub fn with_jump_range(mut self, range: isize) -> Generator {
+        self.jump_range = Some(range);
+        self
+    }
+    /// Only jumps forward, and never past the end, so every program of `acc`,
+    /// `jmp` and `nop` runs to completion.
+    pub fn terminating(mut self) -> Generator {
+        self.terminating = true;
+        self
+    }
+    pub fn generate<R: Rng>(&self, rng: &mut R) -> Vec<Instruction> {
+        let len = rng.gen_range(self.length.clone());
+        let total: u32 = self.mix.iter().map(|(_, weight)| weight).sum();
+        (0..len)
+            .map(|index| {
+                let mut pick = rng.gen_range(0..total);
+                let (operation, _) = self
+                    .mix
+                    .iter()
+                    .find(|(_, weight)| {
+                        if pick < *weight {
+                            return true;
+                        }
+                        pick -= weight;
+                        false
+                    })
+                    .expect("picks are below the total weight");
+                let operation = match operation.register() {
+                    Some(_) => operation.with_register(match rng.gen_range(0..4) {
+                        0 => Register::Accumulator,
+                        letter => Register::Named(letter - 1),
+                    }),
+                    None => operation.clone(),
+                };
+                // `tgl` and anything that may jump take an offset into the
+                // program.
+                let argument = if operation == Operation::Toggle
+                    || operation.flows(1).contains(&Flow::Jump(1))
+                {
+                    self.jump(rng, index, len)
+                } else {
+                    self.argument(rng)
+                };
+                Instruction(operation, argument)
+            })
+            .collect()
+    }
+    fn argument<R: Rng>(&self, rng: &mut R) -> isize {
+        rng.gen_range(-self.argument_range..=self.argument_range)
+    }
+    fn jump<R: Rng>(&self, rng: &mut R, index: usize, len: usize) -> isize {
+        let range = self.jump_range.unwrap_or(len as isize).max(1);
+        if self.terminating {
+            rng.gen_range(1..=range.min((len - index) as isize))
+        } else {
+            rng.gen_range(-range..=range)
+        }
+    }
+}
+
+/// Two ways of running the same program that halted differently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disagreement {
+    pub program: Vec<Instruction>,
+    pub engine: &'static str,
+    pub expected: Halt,
+    pub found: Halt,
+}
+
+/// Runs `program` on `Machine`, and on every other engine that can run it,
+/// and returns how it halts when they all agree.
+pub fn agree(program: &[Instruction]) -> Result<Halt, Disagreement> {
+    let expected = Machine::new(program.to_vec()).run_to_first();
+    let mut others = vec![];
+    if let Ok(bytecode) = Bytecode::decode(program) {
+        others.push(("bytecode", bytecode.run()));
+    }
+    if let Ok(optimized) = optimize(program) {
+        let halt = Machine::new(optimized.program().to_vec()).run_to_first();
+        others.push(("optimize", optimized.translate(&halt)));
+    }
+    match others.into_iter().find(|(_, found)| *found != expected) {
+        Some((engine, found)) => Err(Disagreement {
+            program: program.to_vec(),
+            engine,
+            expected,
+            found,
+        }),
+        None => Ok(expected),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        load_machine,
+        opcode::{Jz, Mul, Registry},
+        Instruction, Operation,
+    };
+    use super::{agree, Generator};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::HashSet;
+
+    #[test]
+    fn engines_agree() {
+        let mut rng = StdRng::seed_from_u64(20);
+        let generators = [
+            Generator::default(),
+            Generator::default().with_length(0..200).with_mix(1, 3, 1),
+            Generator::default().with_jump_range(3),
+        ];
+        for generator in &generators {
+            for _ in 0..500 {
+                let program = generator.generate(&mut rng);
+                agree(&program).expect("engines agree");
+            }
+        }
+    }
+
+    #[test]
+    fn whole_registry() {
+        let mut rng = StdRng::seed_from_u64(20);
+        let mut registry = Registry::default();
+        registry.register(Mul).register(Jz);
+        let generator = Generator::default()
+            .with_length(0..100)
+            .with_registry(&registry);
+        let mut names = HashSet::new();
+        for _ in 0..200 {
+            let program = generator.generate(&mut rng);
+            names.extend(
+                program
+                    .iter()
+                    .map(|instruction| instruction.0.name().to_string()),
+            );
+            agree(&program).expect("engines agree");
+        }
+        let expected: HashSet<_> = registry
+            .operations()
+            .iter()
+            .map(|operation| operation.name().to_string())
+            .collect();
+        assert_eq!(names, expected);
+
+        let program = Generator::default()
+            .with_mix(0, 0, 0)
+            .with_weight(Operation::Toggle, 1)
+            .with_jump_range(2)
+            .generate(&mut rng);
+        assert!(program.iter().all(|Instruction(operation, argument)| {
+            *operation == Operation::Toggle && (-2..=2).contains(argument)
+        }));
+    }
+
+    #[test]
+    fn terminating() {
+        let mut rng = StdRng::seed_from_u64(20);
+        let generator = Generator::default()
+            .with_length(1..100)
+            .with_mix(0, 1, 1)
+            .with_jump_range(5)
+            .terminating();
+        for _ in 0..500 {
+            let program = generator.generate(&mut rng);
+            assert!(program.iter().all(|Instruction(operation, argument)| {
+                *operation == Operation::Nop || (1..=5).contains(argument)
+            }));
+            assert!(agree(&program).expect("engines agree").is_terminated());
+        }
+    }
+
+    #[test]
+    fn malformed_input_does_not_panic() {
+        let mut rng = StdRng::seed_from_u64(20);
+        let alphabet: Vec<char> = "accjmpnoptgl +-0123456789:;\n\r\t _xé".chars().collect();
+        for _ in 0..5000 {
+            let len = rng.gen_range(0..40);
+            let text: String = (0..len)
+                .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                .collect();
+            let _ = load_machine(&text);
+        }
+    }
+}
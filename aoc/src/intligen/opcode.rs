@@ -0,0 +1,239 @@
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use nom::IResult;
+
+use super::{parse, Operation, Register, State};
+
+/// How execution continues after an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Fall through to the next instruction.
+    Next,
+    /// Jump by a relative offset.
+    Jump(isize),
+    /// Stop, the same as running past the last instruction.
+    Halt,
+    /// Stop because the instruction could not run.
+    Fault(Fault),
+    /// Jump to a place only known at run time. Only for `flows`, an
+    /// instruction that returns it from `execute` faults.
+    Indirect,
+}
+
+/// Why an instruction could not run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// There is no memory cell at this address.
+    BadAddress(isize),
+    /// `execute` returned `Flow::Indirect`.
+    Indirect,
+    /// `in` ran with nothing to read.
+    NoInput,
+    /// The result did not fit, and the machine checks for overflow.
+    Overflow,
+}
+
+/// An instruction that can be added to the language without touching the
+/// interpreter. Register it in a `Registry` and load programs with
+/// `load_machine_with`.
+pub trait Opcode: fmt::Debug + Send + Sync {
+    /// The mnemonic, as written in programs.
+    fn name(&self) -> &str;
+    /// Parses what follows the mnemonic and a single space.
+    fn parse_argument<'a>(&self, input: &'a str) -> IResult<&'a str, isize> {
+        parse::get_isize(input)
+    }
+    fn execute(&self, argument: isize, state: &mut State) -> Flow;
+    /// Every way execution may continue, for the static analyses in `cfg`.
+    fn flows(&self, _argument: isize) -> Vec<Flow> {
+        vec![Flow::Next]
+    }
+}
+
+/// A registered opcode. Two are equal, and hash the same, when they have the
+/// same name.
+#[derive(Clone)]
+pub struct Custom(pub Arc<dyn Opcode>);
+
+impl fmt::Debug for Custom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Custom({})", self.0.name())
+    }
+}
+
+impl PartialEq for Custom {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.name() == other.0.name()
+    }
+}
+
+impl Eq for Custom {}
+
+impl Hash for Custom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.name().hash(state);
+    }
+}
+
+/// The operations the parser accepts, tried in order.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    operations: Vec<Operation>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            operations: vec![
+                Operation::Acc,
+                Operation::Jmp,
+                Operation::Nop,
+                Operation::Add(Register::Accumulator),
+                Operation::Load(Register::Accumulator),
+                Operation::Store(Register::Accumulator),
+                Operation::JumpRegister(Register::Accumulator),
+                Operation::In(Register::Accumulator),
+                Operation::Out(Register::Accumulator),
+                Operation::Toggle,
+            ],
+        }
+    }
+}
+
+impl Registry {
+    /// Adds `opcode`, replacing any operation with the same name.
+    pub fn register<O: Opcode + 'static>(&mut self, opcode: O) -> &mut Registry {
+        let operation = Operation::Custom(Custom(Arc::new(opcode)));
+        match self
+            .operations
+            .iter()
+            .position(|known| known.name() == operation.name())
+        {
+            Some(index) => self.operations[index] = operation,
+            None => self.operations.push(operation),
+        }
+        self
+    }
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+    pub fn get(&self, name: &str) -> Option<&Operation> {
+        self.operations
+            .iter()
+            .find(|operation| operation.name() == name)
+    }
+}
+
+/// `mul +N` multiplies the accumulator by `N`. Faults when the product does
+/// not fit, whatever the overflow policy.
+#[derive(Debug, Clone, Copy)]
+pub struct Mul;
+
+impl Opcode for Mul {
+    fn name(&self) -> &str {
+        "mul"
+    }
+    fn execute(&self, argument: isize, state: &mut State) -> Flow {
+        match state.accumulator.checked_mul(argument) {
+            Some(product) => {
+                state.set(Register::Accumulator, product);
+                Flow::Next
+            }
+            None => Flow::Fault(Fault::Overflow),
+        }
+    }
+}
+
+/// `jz +N` jumps by `N` when the accumulator is zero.
+#[derive(Debug, Clone, Copy)]
+pub struct Jz;
+
+impl Opcode for Jz {
+    fn name(&self) -> &str {
+        "jz"
+    }
+    fn execute(&self, argument: isize, state: &mut State) -> Flow {
+        if state.accumulator == 0 {
+            Flow::Jump(argument)
+        } else {
+            Flow::Next
+        }
+    }
+    fn flows(&self, argument: isize) -> Vec<Flow> {
+        vec![Flow::Next, Flow::Jump(argument)]
+    }
+}
+
+/// `hlt +0` stops the program. The argument is ignored.
+#[derive(Debug, Clone, Copy)]
+pub struct Hlt;
+
+impl Opcode for Hlt {
+    fn name(&self) -> &str {
+        "hlt"
+    }
+    fn execute(&self, _argument: isize, _state: &mut State) -> Flow {
+        Flow::Halt
+    }
+    fn flows(&self, _argument: isize) -> Vec<Flow> {
+        vec![Flow::Halt]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{load_machine_with, Halt, Program};
+    use super::{Flow, Hlt, Jz, Mul, Opcode, Registry};
+    use crate::intligen::State;
+
+    fn extended() -> Registry {
+        let mut registry = Registry::default();
+        registry.register(Mul).register(Jz).register(Hlt);
+        registry
+    }
+
+    #[test]
+    fn default_has_the_day_8_set() {
+        let registry = Registry::default();
+        let names: Vec<&str> = registry.operations().iter().map(|op| op.name()).collect();
+        assert_eq!(
+            names,
+            vec!["acc", "jmp", "nop", "add", "ld", "st", "jr", "in", "out", "tgl"]
+        );
+        assert!(registry.get("mul").is_none());
+        assert!("mul +2".parse::<Program>().is_err());
+    }
+
+    #[test]
+    fn registered_opcodes_run() {
+        let program = "acc +3\nmul +4\nacc -12\njz +2\nacc +100\nhlt +0\nacc +1000";
+        let mut machine = load_machine_with(&extended(), program).expect("program");
+        assert_eq!(machine.program().len(), 7);
+        assert_eq!(machine.program()[1].to_string(), "mul +4");
+        assert_eq!(machine.run_to_first(), Halt::Terminated { accumulator: 0 });
+    }
+
+    #[test]
+    fn register_replaces_by_name() {
+        #[derive(Debug)]
+        struct Twice;
+        impl Opcode for Twice {
+            fn name(&self) -> &str {
+                "acc"
+            }
+            fn execute(&self, argument: isize, state: &mut State) -> Flow {
+                state.accumulator += 2 * argument;
+                Flow::Next
+            }
+        }
+        let mut registry = Registry::default();
+        registry.register(Twice);
+        assert_eq!(registry.operations().len(), 10);
+        let mut machine = load_machine_with(&registry, "acc +2\nacc +3").expect("program");
+        assert_eq!(machine.run_to_first().accumulator(), 10);
+    }
+}
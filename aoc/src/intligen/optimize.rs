@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+
+use super::{Halt, Instruction, Operation};
+
+/// A program after `optimize`, with the way back to the original.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Optimized {
+    program: Vec<Instruction>,
+    sources: Vec<Vec<usize>>,
+    original: Vec<Instruction>,
+}
+
+impl Optimized {
+    /// Only for running: `nop` arguments are gone, so it cannot be repaired.
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+    /// The original instructions that instruction `index` stands for, in
+    /// the order they ran.
+    pub fn sources(&self, index: usize) -> &[usize] {
+        &self.sources[index]
+    }
+    /// Turns how the optimized program halted into how the original one
+    /// does, with the program counters of the original.
+    pub fn translate(&self, halt: &Halt) -> Halt {
+        match halt {
+            Halt::InfiniteLoop {
+                cycle, accumulator, ..
+            } => {
+                let mut cycle: Vec<usize> = cycle
+                    .iter()
+                    .flat_map(|index| self.sources[*index].iter().copied())
+                    .collect();
+                let entry = self.entry(&cycle);
+                let start = cycle.iter().position(|pc| *pc == entry).unwrap_or_default();
+                cycle.rotate_left(start);
+                Halt::InfiniteLoop {
+                    entry,
+                    cycle,
+                    accumulator: *accumulator,
+                }
+            }
+            Halt::OutOfBounds {
+                program_counter,
+                accumulator,
+                ..
+            } => {
+                let program_counter = *self.sources[*program_counter]
+                    .last()
+                    .expect("every instruction has a source");
+                Halt::OutOfBounds {
+                    program_counter,
+                    target: (program_counter as isize)
+                        .saturating_add(self.original[program_counter].1),
+                    accumulator: *accumulator,
+                }
+            }
+            Halt::Fault {
+                program_counter,
+                fault,
+                accumulator,
+            } => {
+                // Only `acc` faults, and a run of them faults at the first
+                // one the accumulator does not fit after.
+                let sources = &self.sources[*program_counter];
+                let mut accumulator = *accumulator;
+                let program_counter = sources
+                    .iter()
+                    .copied()
+                    .find(|index| match self.original[*index] {
+                        Instruction(Operation::Acc, argument) => {
+                            match accumulator.checked_add(argument) {
+                                Some(sum) => {
+                                    accumulator = sum;
+                                    false
+                                }
+                                None => true,
+                            }
+                        }
+                        _ => false,
+                    })
+                    .unwrap_or(*sources.last().expect("every instruction has a source"));
+                Halt::Fault {
+                    program_counter,
+                    fault: *fault,
+                    accumulator,
+                }
+            }
+            other => other.clone(),
+        }
+    }
+    /// The original run gets into the loop at the first of its instructions
+    /// it reaches from the start.
+    fn entry(&self, cycle: &[usize]) -> usize {
+        let on_cycle: HashSet<usize> = cycle.iter().copied().collect();
+        let mut program_counter = 0;
+        while !on_cycle.contains(&program_counter) && program_counter < self.original.len() {
+            program_counter = next(&self.original, program_counter) as usize;
+        }
+        program_counter
+    }
+}
+
+/// Where the instruction at `index` goes, as an absolute index.
+fn next(program: &[Instruction], index: usize) -> isize {
+    match program[index] {
+        Instruction(Operation::Jmp, offset) => (index as isize).saturating_add(offset),
+        _ => index as isize + 1,
+    }
+}
+
+/// Where the instruction at `index` ends up going when it is a `jmp` that
+/// lands on other `jmp`s, and the ones it passes on the way.
+fn thread(program: &[Instruction], index: usize) -> (isize, Vec<usize>) {
+    let mut target = next(program, index);
+    let mut passed = vec![];
+    if program[index].0 != Operation::Jmp {
+        return (target, passed);
+    }
+    while target >= 0
+        && (target as usize) < program.len()
+        && program[target as usize].0 == Operation::Jmp
+        && target as usize != index
+        && !passed.contains(&(target as usize))
+    {
+        passed.push(target as usize);
+        target = next(program, target as usize);
+    }
+    (target, passed)
+}
+
+/// Rewrites a program with only `acc`, `jmp` and `nop` so that it runs in
+/// fewer steps and halts the same way under `Overflow::Checked`: runs of
+/// `acc` and `nop` become a single instruction, jumps to jumps go straight
+/// to where the last one goes, and what can never run is dropped. A run
+/// only adds numbers of one sign, so that it overflows exactly when one of
+/// its `acc`s would have. Fails with the index of the first instruction of
+/// any other kind.
+pub fn optimize(program: &[Instruction]) -> Result<Optimized, usize> {
+    if let Some(index) = program.iter().position(|instruction| {
+        !matches!(
+            instruction.0,
+            Operation::Acc | Operation::Jmp | Operation::Nop
+        )
+    }) {
+        return Err(index);
+    }
+    let len = program.len();
+    let in_range = |target: isize| target >= 0 && (target as usize) < len;
+    let threaded: Vec<(isize, Vec<usize>)> = (0..len).map(|index| thread(program, index)).collect();
+
+    let mut reachable = vec![false; len];
+    let mut leader = vec![false; len];
+    let mut pending = if len > 0 { vec![0] } else { vec![] };
+    while let Some(index) = pending.pop() {
+        if reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+        let target = threaded[index].0;
+        if in_range(target) {
+            if program[index].0 == Operation::Jmp {
+                leader[target as usize] = true;
+            }
+            pending.push(target as usize);
+        }
+    }
+    if len > 0 {
+        leader[0] = true;
+    }
+
+    let mut optimized = vec![];
+    let mut sources = vec![];
+    let mut new_index = vec![0; len];
+    let mut jumps = vec![];
+    let mut index = 0;
+    while index < len {
+        if !reachable[index] {
+            index += 1;
+            continue;
+        }
+        new_index[index] = optimized.len();
+        if program[index].0 == Operation::Jmp {
+            let (target, passed) = &threaded[index];
+            jumps.push((optimized.len(), *target));
+            optimized.push(Instruction(Operation::Jmp, 0));
+            sources.push(
+                std::iter::once(index)
+                    .chain(passed.iter().copied())
+                    .collect(),
+            );
+            index += 1;
+            continue;
+        }
+        let start = index;
+        let mut sum: isize = 0;
+        let mut accumulates = false;
+        loop {
+            if program[index].0 == Operation::Acc {
+                // Adding a negative number after a positive one could take
+                // back an overflow, so the run is cut in two there, as it
+                // is when the sum does not fit.
+                if sum.signum() * program[index].1.signum() < 0 {
+                    break;
+                }
+                sum = match sum.checked_add(program[index].1) {
+                    Some(sum) => sum,
+                    None => break,
+                };
+                accumulates = true;
+            }
+            index += 1;
+            if index == len || leader[index] || program[index].0 == Operation::Jmp {
+                break;
+            }
+        }
+        optimized.push(if accumulates {
+            Instruction(Operation::Acc, sum)
+        } else {
+            Instruction(Operation::Nop, 0)
+        });
+        sources.push((start..index).collect());
+    }
+
+    let new_len = optimized.len() as isize;
+    for (at, target) in jumps {
+        let target = if in_range(target) {
+            new_index[target as usize] as isize
+        } else if target < 0 {
+            target
+        } else {
+            new_len + (target - len as isize)
+        };
+        optimized[at].1 = target.saturating_sub(at as isize);
+    }
+    Ok(Optimized {
+        program: optimized,
+        sources,
+        original: program.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        load_machine, tests::random_program, tests::PROGRAM, Instruction, Machine, Operation,
+        Program,
+    };
+    use super::optimize;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn optimized(text: &str) -> String {
+        let program: Program = text.parse().expect("program");
+        Program(optimize(&program.0).expect("optimize").program().to_vec()).to_string()
+    }
+
+    #[test]
+    fn peephole() {
+        assert_eq!(optimized("acc +1\nacc +2\nnop +5\nacc +1"), "acc +4");
+        assert_eq!(
+            optimized("acc +1\nacc +2\nnop +5\nacc -1\nacc -2"),
+            "acc +3\nacc -3"
+        );
+        assert_eq!(
+            optimized("nop +1\nnop -1\njmp +2\nacc +9"),
+            "nop +0\njmp +1"
+        );
+        assert_eq!(
+            optimized("jmp +2\nacc +1\njmp +2\nacc +4\nacc +5\njmp -3"),
+            "jmp +1\nacc +5\njmp -1"
+        );
+        assert_eq!(
+            optimized("acc +1\njmp +1\nacc +2\njmp -2"),
+            "acc +1\njmp +1\nacc +2\njmp -1"
+        );
+    }
+
+    #[test]
+    fn sources() {
+        let program = load_machine(PROGRAM).expect("program").program().to_vec();
+        let optimized = optimize(&program).expect("optimize");
+        assert_eq!(optimized.program().len(), 7);
+        assert_eq!(optimized.sources(2), &[2]);
+        assert_eq!(optimized.sources(5), &[6]);
+        let program = load_machine("acc +1\nadd a +2")
+            .expect("program")
+            .program()
+            .to_vec();
+        assert_eq!(optimize(&program), Err(1));
+    }
+
+    fn assert_same_halt(program: &[Instruction]) {
+        let optimized = optimize(program).expect("optimize");
+        let halt = Machine::new(optimized.program().to_vec()).run_to_first();
+        assert_eq!(
+            optimized.translate(&halt),
+            Machine::new(program.to_vec()).run_to_first(),
+            "{:?}",
+            program
+        );
+    }
+
+    #[test]
+    fn same_halt() {
+        let mut rng = StdRng::seed_from_u64(17);
+        for _ in 0..2000 {
+            let program = random_program(&mut rng);
+            assert_same_halt(&program);
+            // The same program with numbers big enough to overflow after a
+            // few `acc`s.
+            let big: Vec<_> = program
+                .into_iter()
+                .map(|Instruction(operation, argument)| match operation {
+                    Operation::Acc => {
+                        Instruction(operation, argument.signum() * (isize::MAX / 4) + argument)
+                    }
+                    _ => Instruction(operation, argument),
+                })
+                .collect();
+            assert_same_halt(&big);
+        }
+        for program in &[
+            "nop +0\nacc +9223372036854775807\nacc +1",
+            "acc +9223372036854775807\nacc +2\nacc -3",
+            "acc +1\nacc +9223372036854775806\nacc +1\nacc +5",
+            "acc -9223372036854775807\nnop +0\nacc -1\nacc -1\njmp -4",
+            "acc +9223372036854775807\nacc -1\nacc +1\nacc +1",
+        ] {
+            let program: Program = program.parse().expect("program");
+            assert_same_halt(&program.0);
+        }
+    }
+}
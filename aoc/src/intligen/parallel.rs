@@ -0,0 +1,131 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use tracing::info;
+
+use super::{Halt, Machine, Repair};
+
+/// How many steps a patched machine runs between looking for a reason to
+/// give up.
+const CHECK_EVERY: usize = 1024;
+
+impl Machine {
+    /// Same answer as `repair`, with the patched programs run on `threads`
+    /// threads. Candidates are handed out in program order, and once one
+    /// terminates every candidate after it is abandoned, so the swap found
+    /// is always the first one that works.
+    pub fn repair_parallel(&self, threads: usize) -> Result<Repair, Halt> {
+        let mut unpatched = self.with_program(self.program.clone());
+        let original = unpatched.run();
+        if original.is_terminated() {
+            return Err(original);
+        }
+        let candidates: Vec<usize> = (0..self.program.len())
+            .filter(|index| unpatched.hits[*index] > 0 && self.program[*index].toggled().is_some())
+            .collect();
+        let next = AtomicUsize::new(0);
+        // The position in `candidates` of the first swap known to work.
+        let best = AtomicUsize::new(usize::MAX);
+        let found = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads.max(1))
+                .map(|_| scope.spawn(|| self.repair_worker(&candidates, &next, &best)))
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("repair worker panicked"))
+                .min_by_key(|(position, _)| *position)
+        });
+        match found {
+            Some((_, repair)) => {
+                info!("Swapping {} made the program terminate", repair.index);
+                Ok(repair)
+            }
+            None => Err(original),
+        }
+    }
+    /// Takes candidates until they run out or come after one that worked,
+    /// and returns the ones that made the program terminate.
+    fn repair_worker(
+        &self,
+        candidates: &[usize],
+        next: &AtomicUsize,
+        best: &AtomicUsize,
+    ) -> Vec<(usize, Repair)> {
+        let mut found = vec![];
+        loop {
+            let position = next.fetch_add(1, Ordering::Relaxed);
+            if position >= candidates.len() || position > best.load(Ordering::Relaxed) {
+                return found;
+            }
+            let index = candidates[position];
+            let before = self.program[index].clone();
+            let after = before.toggled().expect("candidates are jmp or nop");
+            let mut program = self.program.clone();
+            program[index] = after.clone();
+            let mut machine = self.with_program(program);
+            let cancelled = || best.load(Ordering::Relaxed) < position;
+            if let Some(Halt::Terminated { accumulator }) = machine.run_unless(cancelled) {
+                best.fetch_min(position, Ordering::Relaxed);
+                found.push((
+                    position,
+                    Repair {
+                        index,
+                        before,
+                        after,
+                        accumulator,
+                    },
+                ));
+            }
+        }
+    }
+    /// Like `run`, but gives up and returns `None` once `cancelled` says so.
+    fn run_unless(&mut self, cancelled: impl Fn() -> bool) -> Option<Halt> {
+        loop {
+            for _ in 0..CHECK_EVERY {
+                if let Some(halt) = self.step() {
+                    return Some(halt);
+                }
+            }
+            if cancelled() {
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{generate::Generator, load_machine, tests::PROGRAM, Machine};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn sample() {
+        let machine = load_machine(PROGRAM).expect("program");
+        let repair = machine.repair_parallel(4).expect("repair");
+        assert_eq!(repair.index, 7);
+        assert_eq!(repair.accumulator, 8);
+    }
+
+    #[test]
+    fn same_as_sequential() {
+        let mut rng = StdRng::seed_from_u64(22);
+        let generator = Generator::default().with_length(1..60).with_jump_range(4);
+        let mut repaired = 0;
+        for _ in 0..300 {
+            let machine = Machine::new(generator.generate(&mut rng));
+            let expected = machine.repair();
+            repaired += expected.is_ok() as usize;
+            for threads in &[1, 3, 8] {
+                assert_eq!(
+                    machine.repair_parallel(*threads),
+                    expected,
+                    "{:?}",
+                    machine.program()
+                );
+            }
+        }
+        assert!(repaired > 0);
+    }
+}
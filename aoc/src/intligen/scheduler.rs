@@ -0,0 +1,166 @@
+use super::{Halt, Machine};
+
+/// How a `Scheduler` run ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every machine stopped for a reason other than waiting for input.
+    Finished(Vec<Halt>),
+    /// No machine can run. The machines in `blocked` wait for input that
+    /// will never come, `halts` has the state of all of them.
+    Deadlock {
+        blocked: Vec<usize>,
+        halts: Vec<Halt>,
+    },
+}
+
+/// Runs several machines in turn, moving everything a machine writes to the
+/// input of the machine it is wired to.
+pub struct Scheduler {
+    machines: Vec<Machine>,
+    wires: Vec<Option<usize>>,
+    quantum: usize,
+}
+
+impl Scheduler {
+    /// Each machine feeds the next. The output of the last one is kept.
+    pub fn pipeline(machines: Vec<Machine>) -> Scheduler {
+        let count = machines.len();
+        let wires = (0..count)
+            .map(|index| Some(index + 1).filter(|next| *next < count))
+            .collect();
+        Scheduler {
+            machines,
+            wires,
+            quantum: 1000,
+        }
+    }
+    /// Each machine feeds the next, and the last one feeds the first.
+    pub fn ring(machines: Vec<Machine>) -> Scheduler {
+        let count = machines.len();
+        let wires = (0..count).map(|index| Some((index + 1) % count)).collect();
+        Scheduler {
+            machines,
+            wires,
+            quantum: 1000,
+        }
+    }
+    /// Runs each machine for at most `steps` instructions per turn.
+    pub fn with_quantum(mut self, steps: usize) -> Scheduler {
+        self.quantum = steps.max(1);
+        self
+    }
+    pub fn machines(&self) -> &[Machine] {
+        &self.machines
+    }
+    pub fn machine_mut(&mut self, index: usize) -> &mut Machine {
+        &mut self.machines[index]
+    }
+    /// Takes turns until every machine has stopped, or until a whole round
+    /// goes by without any machine running an instruction.
+    pub fn run(&mut self) -> Outcome {
+        let mut halts: Vec<Option<Halt>> = vec![None; self.machines.len()];
+        loop {
+            let mut progress = false;
+            for (index, halt) in halts.iter_mut().enumerate() {
+                if halt
+                    .as_ref()
+                    .is_some_and(|halt| !matches!(halt, Halt::Blocked { .. }))
+                {
+                    continue;
+                }
+                let machine = &mut self.machines[index];
+                let before = machine.steps();
+                *halt = (0..self.quantum).find_map(|_| machine.step());
+                progress |= machine.steps() != before;
+                self.deliver(index);
+            }
+            if halts.iter().all(|halt| {
+                halt.as_ref()
+                    .is_some_and(|halt| !matches!(halt, Halt::Blocked { .. }))
+            }) {
+                return Outcome::Finished(halts.into_iter().flatten().collect());
+            }
+            if !progress {
+                let blocked = halts
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, halt)| matches!(halt, Some(Halt::Blocked { .. })))
+                    .map(|(index, _)| index)
+                    .collect();
+                return Outcome::Deadlock {
+                    blocked,
+                    halts: halts.into_iter().flatten().collect(),
+                };
+            }
+        }
+    }
+    fn deliver(&mut self, from: usize) {
+        if let Some(to) = self.wires[from] {
+            for value in self.machines[from].take_output() {
+                self.machines[to].push_input(value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{load_machine, Halt};
+    use super::{Outcome, Scheduler};
+
+    #[test]
+    fn pipeline() {
+        let first = load_machine("in a +0\nout a +1\nin a +0\nout a +1").expect("program");
+        let second = load_machine("in a +0\nout a +10\nin a +0\nout a +10").expect("program");
+        let mut scheduler = Scheduler::pipeline(vec![first, second]).with_quantum(1);
+        scheduler.machine_mut(0).push_input(1);
+        scheduler.machine_mut(0).push_input(2);
+        assert_eq!(
+            scheduler.run(),
+            Outcome::Finished(vec![
+                Halt::Terminated { accumulator: 0 },
+                Halt::Terminated { accumulator: 0 }
+            ])
+        );
+        assert_eq!(scheduler.machine_mut(1).take_output(), vec![12, 13]);
+    }
+
+    #[test]
+    fn ring() {
+        let first = load_machine("out acc +1\nin acc +0\nout acc +1\nin acc +0").expect("program");
+        let second = load_machine("in acc +0\nout acc +1\nin acc +0\nout acc +1").expect("program");
+        let mut scheduler = Scheduler::ring(vec![first, second]);
+        assert_eq!(
+            scheduler.run(),
+            Outcome::Finished(vec![
+                Halt::Terminated { accumulator: 4 },
+                Halt::Terminated { accumulator: 3 }
+            ])
+        );
+    }
+
+    #[test]
+    fn deadlock() {
+        let first = load_machine("in acc +0\nout acc +1").expect("program");
+        let second = load_machine("acc +5\nin acc +0\nout acc +1").expect("program");
+        let third = load_machine("acc +1").expect("program");
+        let mut scheduler = Scheduler::ring(vec![first, second, third]);
+        assert_eq!(
+            scheduler.run(),
+            Outcome::Deadlock {
+                blocked: vec![0, 1],
+                halts: vec![
+                    Halt::Blocked {
+                        program_counter: 0,
+                        accumulator: 0
+                    },
+                    Halt::Blocked {
+                        program_counter: 1,
+                        accumulator: 5
+                    },
+                    Halt::Terminated { accumulator: 1 },
+                ]
+            }
+        );
+    }
+}
@@ -0,0 +1,525 @@
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use num_bigint::BigInt;
+
+use super::{
+    hash_instruction, hash_program, hash_state, opcode::Registry, parse, Edits, Instruction,
+    Machine, Seen, State,
+};
+
+/// The first line of a saved snapshot.
+const HEADER: &str = "intligen snapshot 1";
+
+/// A copy of everything a `Machine` has changed while running, including any
+/// patches to its program. Limits and history are not part of it.
+///
+/// Its `Display` is a text format that `FromStr` reads back, one field per
+/// line as a name followed by its values separated by spaces:
+///
+/// ```text
+/// intligen snapshot 1
+/// program_counter 2
+/// steps 2
+/// self_modified false
+/// visited 0 1
+/// path 0 1
+/// hits 1 1 0 0 0 0 0 0 0
+/// accumulator 1
+/// overflow checked
+/// registers 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0
+/// memory
+/// input
+/// output
+/// program 9
+/// nop +0
+/// acc +1
+/// ...
+/// edits 0
+/// seen 0
+/// ```
+///
+/// `visited` is in increasing order, `path` and `hits` as they are.
+/// `accumulator` up to `output` are the state, with the accumulator written
+/// in full even when it does not fit in an `isize`. `program N` is followed
+/// by its `N` instructions as in a text program. `edits N` is followed by
+/// what `tgl` overwrote since the last input or output, each an `edit` line
+/// with the position in `path` of the step and the index, then the
+/// instruction that was there. `seen N` is followed by `N` entries of the
+/// loop detection that compares whole states, each an `entry` line with the
+/// program counter and its position in `path`, then a state like the one
+/// above. Their programs are the current one with the later edits undone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    state: State,
+    program_counter: usize,
+    program: Vec<Instruction>,
+    visited: HashSet<usize>,
+    path: Vec<usize>,
+    self_modified: bool,
+    seen: Seen,
+    edits: Edits,
+    hits: Vec<usize>,
+    steps: usize,
+}
+
+impl Snapshot {
+    pub fn accumulator(&self) -> isize {
+        self.state.accumulator
+    }
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+    /// Reads what `Display` wrote, for programs using the opcodes in
+    /// `registry`.
+    pub fn parse_with(registry: &Registry, text: &str) -> Result<Snapshot, String> {
+        let mut reader = Reader {
+            lines: text.lines().enumerate(),
+            registry,
+            line: 0,
+        };
+        let (_, header) = reader.next()?;
+        if header.trim_end() != HEADER {
+            return Err(format!("line 1: expected `{}`", HEADER));
+        }
+        let program_counter = reader.number("program_counter")?;
+        let steps = reader.number("steps")?;
+        let self_modified = reader.number("self_modified")?;
+        let visited = reader.numbers("visited")?.into_iter().collect();
+        let path = reader.numbers("path")?;
+        let hits = reader.numbers("hits")?;
+        let state = reader.state()?;
+        let program = reader.program()?;
+        let mut edits = Edits::default();
+        for _ in 0..reader.number::<usize>("edits")? {
+            let edit = reader.numbers("edit")?;
+            if edit.len() != 2 {
+                return Err(reader.error("expected a position and an index"));
+            }
+            if edit[1] >= program.len() {
+                return Err(reader.error(&format!("edit {} is past the program", edit[1])));
+            }
+            edits.push((edit[0], edit[1], reader.instruction()?));
+        }
+        let mut entries = vec![];
+        for _ in 0..reader.number::<usize>("seen")? {
+            let entry = reader.numbers("entry")?;
+            if entry.len() != 2 {
+                return Err(reader.error("expected a program counter and a position"));
+            }
+            entries.push((entry[0], entry[1], reader.state()?));
+        }
+        if let Some((index, _)) = reader.lines.next() {
+            return Err(format!("line {}: unexpected line after `seen`", index + 1));
+        }
+        // `Machine` looks edits up by step.
+        edits.sort_by_key(|(step, _, _)| *step);
+        let seen = fingerprint(&program, &edits, entries);
+
+        let len = program.len();
+        if program_counter > len {
+            return Err(format!(
+                "program counter {} is past the program",
+                program_counter
+            ));
+        }
+        if hits.len() != len {
+            return Err(format!("{} hits for {} instructions", hits.len(), len));
+        }
+        if let Some(index) = path.iter().chain(&visited).find(|index| **index >= len) {
+            return Err(format!("visited {} is past the program", index));
+        }
+        Ok(Snapshot {
+            state,
+            program_counter,
+            program,
+            visited,
+            path,
+            self_modified,
+            seen,
+            edits,
+            hits,
+            steps,
+        })
+    }
+}
+
+/// The `seen` of a machine that had `entries`, by program counter, position
+/// in `path` and state. The program at each position is `program` with the
+/// edits made from then on undone.
+fn fingerprint(
+    program: &[Instruction],
+    edits: &Edits,
+    mut entries: Vec<(usize, usize, State)>,
+) -> Seen {
+    entries.sort_by_key(|(_, position, _)| *position);
+    let mut edits: Vec<_> = edits.iter().collect();
+    let mut program = program.to_vec();
+    let mut hash = hash_program(&program);
+    let mut hashes = vec![0; entries.len()];
+    for ((_, position, _), slot) in entries.iter().zip(&mut hashes).rev() {
+        while let Some((_, index, before)) = edits.pop_if(|(step, _, _)| step >= position) {
+            hash = hash
+                .wrapping_sub(hash_instruction(*index, &program[*index]))
+                .wrapping_add(hash_instruction(*index, before));
+            program[*index] = before.clone();
+        }
+        *slot = hash;
+    }
+    let mut seen = Seen::default();
+    for ((program_counter, position, state), hash) in entries.into_iter().zip(hashes) {
+        seen.entry((program_counter, hash_state(&state), hash))
+            .or_default()
+            .push((position, state));
+    }
+    seen
+}
+
+fn write_numbers<'a, T: fmt::Display + 'a>(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    numbers: impl IntoIterator<Item = &'a T>,
+) -> fmt::Result {
+    write!(f, "{}", name)?;
+    for number in numbers {
+        write!(f, " {}", number)?;
+    }
+    writeln!(f)
+}
+
+fn write_state(f: &mut fmt::Formatter<'_>, state: &State) -> fmt::Result {
+    writeln!(f, "accumulator {}", state.exact_accumulator())?;
+    writeln!(f, "overflow {}", state.overflow)?;
+    write_numbers(f, "registers", &state.registers)?;
+    write_numbers(f, "memory", &state.memory)?;
+    write_numbers(f, "input", &state.input)?;
+    write_numbers(f, "output", &state.output)
+}
+
+fn write_program(f: &mut fmt::Formatter<'_>, program: &[Instruction]) -> fmt::Result {
+    writeln!(f, "program {}", program.len())?;
+    for instruction in program {
+        writeln!(f, "{}", instruction)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "program_counter {}", self.program_counter)?;
+        writeln!(f, "steps {}", self.steps)?;
+        writeln!(f, "self_modified {}", self.self_modified)?;
+        let mut visited: Vec<_> = self.visited.iter().collect();
+        visited.sort_unstable();
+        write_numbers(f, "visited", visited)?;
+        write_numbers(f, "path", &self.path)?;
+        write_numbers(f, "hits", &self.hits)?;
+        write_state(f, &self.state)?;
+        write_program(f, &self.program)?;
+        writeln!(f, "edits {}", self.edits.len())?;
+        for (step, index, before) in &self.edits {
+            writeln!(f, "edit {} {}", step, index)?;
+            writeln!(f, "{}", before)?;
+        }
+        let mut seen: Vec<_> = self
+            .seen
+            .iter()
+            .flat_map(|((program_counter, _, _), visits)| {
+                visits.iter().map(move |visit| (program_counter, visit))
+            })
+            .collect();
+        seen.sort_unstable_by_key(|(_, (position, _))| *position);
+        writeln!(f, "seen {}", seen.len())?;
+        for (program_counter, (position, state)) in seen {
+            writeln!(f, "entry {} {}", program_counter, position)?;
+            write_state(f, state)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Snapshot {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Snapshot::parse_with(&Registry::default(), text)
+    }
+}
+
+struct Reader<'a, I> {
+    lines: I,
+    registry: &'a Registry,
+    /// The line read last, counting from 1.
+    line: usize,
+}
+
+impl<'a, I: Iterator<Item = (usize, &'a str)>> Reader<'a, I> {
+    fn error(&self, message: &str) -> String {
+        format!("line {}: {}", self.line, message)
+    }
+    fn next(&mut self) -> Result<(usize, &'a str), String> {
+        match self.lines.next() {
+            Some((index, line)) => {
+                self.line = index + 1;
+                Ok((self.line, line))
+            }
+            None => Err(format!(
+                "line {}: unexpected end of the snapshot",
+                self.line + 1
+            )),
+        }
+    }
+    /// The values on the next line, which must start with `name`.
+    fn field(&mut self, name: &str) -> Result<Vec<&'a str>, String> {
+        let (_, line) = self.next()?;
+        let mut words = line.split_whitespace();
+        if words.next() != Some(name) {
+            return Err(self.error(&format!("expected `{}`", name)));
+        }
+        Ok(words.collect())
+    }
+    fn numbers<T: FromStr>(&mut self, name: &str) -> Result<Vec<T>, String> {
+        self.field(name)?
+            .into_iter()
+            .map(|word| {
+                word.parse()
+                    .map_err(|_| self.error(&format!("bad value `{}` for `{}`", word, name)))
+            })
+            .collect()
+    }
+    fn number<T: FromStr>(&mut self, name: &str) -> Result<T, String> {
+        let mut numbers = self.numbers(name)?;
+        if numbers.len() != 1 {
+            return Err(self.error(&format!("expected a single value for `{}`", name)));
+        }
+        Ok(numbers.remove(0))
+    }
+    fn state(&mut self) -> Result<State, String> {
+        let mut state = State::default();
+        let accumulator: BigInt = self.number("accumulator")?;
+        state.overflow = self.number("overflow")?;
+        state.set_exact_accumulator(accumulator);
+        let registers: Vec<isize> = self.numbers("registers")?;
+        if registers.len() != state.registers.len() {
+            return Err(self.error(&format!("expected {} registers", state.registers.len())));
+        }
+        state.registers.copy_from_slice(&registers);
+        state.memory = self.numbers("memory")?;
+        state.input = self.numbers("input")?.into_iter().collect();
+        state.output = self.numbers("output")?.into_iter().collect();
+        Ok(state)
+    }
+    fn instruction(&mut self) -> Result<Instruction, String> {
+        let (_, line) = self.next()?;
+        match parse::get_instruction(self.registry, line.trim_end()) {
+            Ok(("", instruction)) => Ok(instruction),
+            _ => Err(self.error(&format!("expected an instruction, found `{}`", line))),
+        }
+    }
+    fn program(&mut self) -> Result<Vec<Instruction>, String> {
+        let len = self.number("program")?;
+        (0..len).map(|_| self.instruction()).collect()
+    }
+}
+
+impl Machine {
+    /// A fresh machine carrying on from `snapshot`, without limits.
+    pub fn resume(snapshot: &Snapshot) -> Machine {
+        let mut machine = Machine::new(snapshot.program.clone());
+        machine.restore(snapshot);
+        machine
+    }
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            state: self.state.clone(),
+            program_counter: self.program_counter,
+            program: self.program.clone(),
+            visited: self.visited.clone(),
+            path: self.path.clone(),
+            self_modified: self.self_modified,
+            seen: self.seen.clone(),
+            edits: self.edits.clone(),
+            hits: self.hits.clone(),
+            steps: self.steps,
+        }
+    }
+    /// Puts the machine back into the state of `snapshot`. The step history
+    /// belongs to the abandoned run, so it is cleared.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.state = snapshot.state.clone();
+        self.program_counter = snapshot.program_counter;
+        self.program = snapshot.program.clone();
+        self.branches_on_state = super::branches_on_state(&self.program);
+        self.visited = snapshot.visited.clone();
+        self.path = snapshot.path.clone();
+        self.self_modified = snapshot.self_modified;
+        self.seen = snapshot.seen.clone();
+        self.edits = snapshot.edits.clone();
+        self.program_hash = None;
+        self.hits = snapshot.hits.clone();
+        self.steps = snapshot.steps;
+        self.history.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        load_machine, load_machine_with, opcode::Jz, opcode::Registry, tests::PROGRAM, Halt,
+        Instruction, Machine, Operation, Overflow,
+    };
+    use super::Snapshot;
+
+    #[test]
+    fn restore() {
+        let mut machine = load_machine(PROGRAM).expect("program");
+        machine.step();
+        machine.step();
+        let snapshot = machine.snapshot();
+        assert_eq!(snapshot.program_counter(), 2);
+        assert_eq!(snapshot.accumulator(), 1);
+
+        let halt = machine.run_to_first();
+        machine.restore(&snapshot);
+        assert_eq!(machine.snapshot(), snapshot);
+        assert_eq!(machine.run_to_first(), halt);
+
+        machine.restore(&snapshot);
+        machine.patch(7, Instruction(Operation::Nop, -4));
+        assert_eq!(machine.run_to_first(), Halt::Terminated { accumulator: 8 });
+        machine.restore(&snapshot);
+        assert_eq!(machine.program()[7], Instruction(Operation::Jmp, -4));
+    }
+
+    #[test]
+    fn step_back_to_start() {
+        let mut machine = load_machine(PROGRAM).expect("program").with_history(100);
+        let start = machine.snapshot();
+        let mut states = vec![];
+        while machine.step().is_none() {
+            states.push(machine.snapshot());
+        }
+        while let Some(state) = states.pop() {
+            assert!(machine.step_back());
+            assert_eq!(machine.snapshot(), state);
+        }
+        assert!(machine.step_back());
+        assert_eq!(machine.snapshot(), start);
+        assert!(!machine.step_back());
+    }
+
+    #[test]
+    fn bounded_history() {
+        let mut machine = load_machine(PROGRAM).expect("program").with_history(2);
+        for _ in 0..5 {
+            machine.step();
+        }
+        assert!(machine.step_back());
+        assert!(machine.step_back());
+        assert!(!machine.step_back());
+        assert_eq!(machine.steps(), 3);
+
+        let snapshot = machine.snapshot();
+        machine.step();
+        machine.restore(&snapshot);
+        assert!(!machine.step_back());
+    }
+
+    #[test]
+    fn save_and_resume() {
+        let mut machine = load_machine(PROGRAM).expect("program");
+        for _ in 0..4 {
+            machine.step();
+        }
+        let text = machine.snapshot().to_string();
+        assert!(text.starts_with(
+            "intligen snapshot 1\n\
+             program_counter 7\n\
+             steps 4\n\
+             self_modified false\n\
+             visited 0 1 2 6\n\
+             path 0 1 2 6\n\
+             hits 1 1 1 0 0 0 1 0 0\n\
+             accumulator 2\n\
+             overflow checked\n"
+        ));
+        assert!(text.ends_with("program 9\nnop +0\nacc +1\njmp +4\nacc +3\njmp -3\nacc -99\nacc +1\njmp -4\nacc +6\nedits 0\nseen 0\n"));
+        let snapshot: Snapshot = text.parse().expect("snapshot");
+        assert_eq!(snapshot, machine.snapshot());
+        let mut resumed = Machine::resume(&snapshot);
+        assert_eq!(resumed.run_to_first(), machine.run_to_first());
+        assert_eq!(resumed.steps(), machine.steps());
+    }
+
+    #[test]
+    fn save_state_loops() {
+        let mut registry = Registry::default();
+        registry.register(Jz);
+        let program = "jz +1\nacc +9223372036854775807\nacc +4\nin a +0\nout a +1\ntgl +1\nnop +2\njmp -2\njmp -3";
+        let mut machine = load_machine_with(&registry, program)
+            .expect("program")
+            .with_overflow(Overflow::Unbounded)
+            .with_memory(2);
+        machine.push_input(7);
+        for _ in 0..12 {
+            machine.step();
+        }
+        let snapshot = machine.snapshot();
+        let text = snapshot.to_string();
+        assert!(!text.contains("\nseen 0\n"));
+        assert!(!text.contains("\nedits 0\n"));
+        // The programs of the entries are not written out, only the edits.
+        assert_eq!(text.matches("\nprogram ").count(), 1);
+        assert!(text.contains("\naccumulator 9223372036854775811\n"));
+        assert!(text.parse::<Snapshot>().is_err());
+        let parsed = Snapshot::parse_with(&registry, &text).expect("snapshot");
+        assert_eq!(parsed, snapshot);
+        let mut resumed = Machine::resume(&parsed);
+        let halt = machine.run_to_first();
+        assert!(matches!(halt, Halt::InfiniteLoop { .. }));
+        assert_eq!(resumed.run_to_first(), halt);
+        assert_eq!(resumed.exact_accumulator(), machine.exact_accumulator());
+    }
+
+    #[test]
+    fn bad_snapshots() {
+        let text = load_machine(PROGRAM)
+            .expect("program")
+            .snapshot()
+            .to_string();
+        let error = |text: &str| text.parse::<Snapshot>().expect_err("error");
+        assert_eq!(
+            error(&text.replace("steps 0", "steps x")),
+            "line 3: bad value `x` for `steps`"
+        );
+        assert_eq!(
+            error(&text.replace("program_counter 0", "program_counter 10")),
+            "program counter 10 is past the program"
+        );
+        assert_eq!(
+            error(&text.replace("acc -99", "acc -x")),
+            "line 20: expected an instruction, found `acc -x`"
+        );
+        assert_eq!(
+            error(&text.replace("\nseen 0\n", "\n")),
+            "line 25: unexpected end of the snapshot"
+        );
+        assert_eq!(
+            error(&text.replace("edits 0", "edits 1\nedit 0 9\nnop +0")),
+            "line 25: edit 9 is past the program"
+        );
+        assert_eq!(
+            error(&format!("{}extra\n", text)),
+            "line 26: unexpected line after `seen`"
+        );
+        assert!(error("intligen snapshot 2").starts_with("line 1"));
+    }
+}
@@ -0,0 +1,265 @@
+use std::{collections::VecDeque, convert::TryFrom, fmt, str::FromStr};
+
+use num_bigint::{BigInt, Sign};
+
+/// Number of lettered registers, `a` through `z`.
+pub const REGISTERS: usize = 26;
+
+/// A register name: the accumulator `acc`, or a single letter `a` to `z`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Register {
+    Accumulator,
+    Named(u8),
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::Accumulator => write!(f, "acc"),
+            Register::Named(index) => write!(f, "{}", (b'a' + index) as char),
+        }
+    }
+}
+
+impl FromStr for Register {
+    type Err = String;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.as_bytes() {
+            b"acc" => Ok(Register::Accumulator),
+            [letter @ b'a'..=b'z'] => Ok(Register::Named(letter - b'a')),
+            _ => Err(format!("not a register: {}", name)),
+        }
+    }
+}
+
+/// What arithmetic does when a result does not fit in an `isize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Overflow {
+    /// The instruction faults with `Fault::Overflow` and changes nothing.
+    #[default]
+    Checked,
+    /// Wraps around, like two's complement hardware.
+    Wrapping,
+    /// Stops at `isize::MIN` or `isize::MAX`.
+    Saturating,
+    /// The accumulator grows as large as it needs to, see
+    /// `State::exact_accumulator`. Registers, jumps and output are still
+    /// `isize`, and overflow there is checked. So is reading the accumulator
+    /// as an `isize` while it does not fit in one, see
+    /// `Operation::execute`.
+    Unbounded,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Overflow::Checked => "checked",
+            Overflow::Wrapping => "wrapping",
+            Overflow::Saturating => "saturating",
+            Overflow::Unbounded => "unbounded",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "checked" => Ok(Overflow::Checked),
+            "wrapping" => Ok(Overflow::Wrapping),
+            "saturating" => Ok(Overflow::Saturating),
+            "unbounded" => Ok(Overflow::Unbounded),
+            _ => Err(format!("not an overflow policy: {}", name)),
+        }
+    }
+}
+
+impl Overflow {
+    /// `value + argument`, or `None` when that overflows and this policy
+    /// does not say what to do instead.
+    pub fn add(self, value: isize, argument: isize) -> Option<isize> {
+        match self {
+            Overflow::Checked | Overflow::Unbounded => value.checked_add(argument),
+            Overflow::Wrapping => Some(value.wrapping_add(argument)),
+            Overflow::Saturating => Some(value.saturating_add(argument)),
+        }
+    }
+}
+
+/// The values instructions work on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct State {
+    /// Wrapped around once it no longer fits under `Overflow::Unbounded`.
+    pub accumulator: isize,
+    /// The whole accumulator, only while it does not fit in `accumulator`.
+    wide: Option<BigInt>,
+    pub overflow: Overflow,
+    pub registers: [isize; REGISTERS],
+    pub memory: Vec<isize>,
+    /// Values waiting to be read by `in`.
+    pub input: VecDeque<isize>,
+    /// Values written by `out`.
+    pub output: VecDeque<isize>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            accumulator: 0,
+            wide: None,
+            overflow: Overflow::default(),
+            registers: [0; REGISTERS],
+            memory: vec![],
+            input: VecDeque::default(),
+            output: VecDeque::default(),
+        }
+    }
+}
+
+impl State {
+    /// The accumulator wraps around, see `accumulator_wrapped`.
+    pub fn get(&self, register: Register) -> isize {
+        match register {
+            Register::Accumulator => self.accumulator,
+            Register::Named(index) => self.registers[index as usize],
+        }
+    }
+    pub fn set(&mut self, register: Register, value: isize) {
+        match register {
+            Register::Accumulator => {
+                self.accumulator = value;
+                self.wide = None;
+            }
+            Register::Named(index) => self.registers[index as usize] = value,
+        }
+    }
+    /// Adds `argument` to `register` under the overflow policy and returns
+    /// the new value. `None`, with the register unchanged, when it would
+    /// overflow and the policy is `Checked`.
+    pub fn add(&mut self, register: Register, argument: isize) -> Option<isize> {
+        if register == Register::Accumulator && self.overflow == Overflow::Unbounded {
+            let wide = self.exact_accumulator() + argument;
+            self.accumulator = self.accumulator.wrapping_add(argument);
+            self.wide = match isize::try_from(&wide) {
+                Ok(_) => None,
+                Err(_) => Some(wide),
+            };
+            return Some(self.accumulator);
+        }
+        let value = self.overflow.add(self.get(register), argument)?;
+        self.set(register, value);
+        Some(value)
+    }
+    /// The accumulator as it would be if it never overflowed. Only differs
+    /// from `accumulator` under `Overflow::Unbounded`.
+    pub fn exact_accumulator(&self) -> BigInt {
+        match &self.wide {
+            Some(wide) => wide.clone(),
+            None => BigInt::from(self.accumulator),
+        }
+    }
+    /// Whether `accumulator` has wrapped around and differs from
+    /// `exact_accumulator`.
+    pub fn accumulator_wrapped(&self) -> bool {
+        self.wide.is_some()
+    }
+    /// Sets the accumulator to `value`, wrapped around in `accumulator` when
+    /// it does not fit.
+    pub fn set_exact_accumulator(&mut self, value: BigInt) {
+        let low = value.iter_u64_digits().next().unwrap_or_default() as isize;
+        self.accumulator = match value.sign() {
+            Sign::Minus => low.wrapping_neg(),
+            _ => low,
+        };
+        self.wide = match isize::try_from(&value) {
+            Ok(_) => None,
+            Err(_) => Some(value),
+        };
+    }
+    /// The memory cell at `address`, if there is one.
+    pub fn cell(&mut self, address: isize) -> Option<&mut isize> {
+        if address < 0 {
+            None
+        } else {
+            self.memory.get_mut(address as usize)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Overflow, Register, State};
+    use num_bigint::BigInt;
+
+    #[test]
+    fn names() {
+        assert_eq!("acc".parse(), Ok(Register::Accumulator));
+        assert_eq!("c".parse(), Ok(Register::Named(2)));
+        assert!("A".parse::<Register>().is_err());
+        assert!("ab".parse::<Register>().is_err());
+        assert_eq!(Register::Named(25).to_string(), "z");
+    }
+
+    #[test]
+    fn cells() {
+        let mut state = State {
+            memory: vec![0; 4],
+            ..State::default()
+        };
+        state.set(Register::Named(1), 7);
+        assert_eq!(state.get(Register::Named(1)), 7);
+        *state.cell(3).expect("cell") = 2;
+        assert_eq!(state.memory, vec![0, 0, 0, 2]);
+        assert!(state.cell(4).is_none());
+        assert!(state.cell(-1).is_none());
+    }
+
+    #[test]
+    fn add() {
+        let mut state = State::default();
+        assert_eq!(
+            state.add(Register::Accumulator, isize::MAX),
+            Some(isize::MAX)
+        );
+        assert_eq!(state.add(Register::Accumulator, 1), None);
+        assert_eq!(state.accumulator, isize::MAX);
+        state.overflow = Overflow::Unbounded;
+        assert_eq!(state.add(Register::Accumulator, 2), Some(isize::MIN + 1));
+        assert_eq!(
+            state.exact_accumulator(),
+            BigInt::from(isize::MAX) + BigInt::from(2)
+        );
+        state.add(Register::Accumulator, -2);
+        assert_eq!(state.exact_accumulator(), BigInt::from(isize::MAX));
+        assert_eq!(
+            state,
+            State {
+                accumulator: isize::MAX,
+                overflow: Overflow::Unbounded,
+                ..State::default()
+            }
+        );
+        assert_eq!(state.add(Register::Named(0), isize::MIN), Some(isize::MIN));
+        assert_eq!(state.add(Register::Named(0), -1), None);
+    }
+
+    #[test]
+    fn exact_accumulator() {
+        let mut state = State {
+            overflow: Overflow::Unbounded,
+            ..State::default()
+        };
+        state.add(Register::Accumulator, isize::MIN);
+        state.add(Register::Accumulator, -5);
+        let mut copy = State {
+            overflow: "unbounded".parse().expect("overflow"),
+            ..State::default()
+        };
+        copy.set_exact_accumulator(state.exact_accumulator());
+        assert_eq!(copy, state);
+        copy.set_exact_accumulator(BigInt::from(-3));
+        assert_eq!(copy.accumulator, -3);
+        assert_eq!(Overflow::Saturating.to_string(), "saturating");
+    }
+}
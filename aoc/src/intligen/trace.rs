@@ -0,0 +1,226 @@
+use std::io;
+
+use super::Instruction;
+
+/// One executed instruction. `step` counts from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub step: usize,
+    pub program_counter: usize,
+    pub instruction: Instruction,
+    pub accumulator_before: isize,
+    pub accumulator_after: isize,
+}
+
+/// Every step a `Machine` ran, in order.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Trace(Vec<TraceEntry>);
+
+impl Trace {
+    pub(super) fn push(&mut self, entry: TraceEntry) {
+        self.0.push(entry);
+    }
+    /// Forgets the entry for `step`, when it is the last one.
+    pub(super) fn pop(&mut self, step: usize) {
+        if self.0.last().is_some_and(|entry| entry.step == step) {
+            self.0.pop();
+        }
+    }
+    pub fn entries(&self) -> &[TraceEntry] {
+        &self.0
+    }
+    /// Index of the first entry where the two traces differ, `None` when
+    /// they are the same.
+    pub fn first_divergence(&self, other: &Trace) -> Option<usize> {
+        let common = self.0.len().min(other.0.len());
+        match (0..common).find(|index| self.0[*index] != other.0[*index]) {
+            None if self.0.len() != other.0.len() => Some(common),
+            divergence => divergence,
+        }
+    }
+    pub fn write_csv<W: io::Write>(&self, writer: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record([
+            "step",
+            "program_counter",
+            "instruction",
+            "accumulator_before",
+            "accumulator_after",
+        ])?;
+        for entry in &self.0 {
+            writer.write_record([
+                entry.step.to_string(),
+                entry.program_counter.to_string(),
+                entry.instruction.to_string(),
+                entry.accumulator_before.to_string(),
+                entry.accumulator_after.to_string(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    /// One JSON object per line, with the same fields as the CSV.
+    pub fn write_json_lines<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
+        for entry in &self.0 {
+            writeln!(
+                writer,
+                "{{\"step\":{},\"program_counter\":{},\"instruction\":\"{}\",\"accumulator_before\":{},\"accumulator_after\":{}}}",
+                entry.step,
+                entry.program_counter,
+                json_escape(&entry.instruction.to_string()),
+                entry.accumulator_before,
+                entry.accumulator_after
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `text` ready to go between the quotes of a JSON string. Opcode names are
+/// whatever was registered, so they may need it.
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            control if control.is_control() => {
+                escaped.push_str(&format!("\\u{:04x}", control as u32))
+            }
+            other => escaped.push(other),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        load_machine,
+        opcode::{Custom, Flow, Opcode},
+        tests::PROGRAM,
+        Instruction, Machine, Operation, State,
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn records_steps() {
+        let mut machine = load_machine(PROGRAM).expect("program").with_trace();
+        machine.run_to_first();
+        let trace = machine.trace().expect("trace");
+        assert_eq!(trace.entries().len(), machine.steps());
+        let last = trace.entries().last().expect("entry");
+        assert_eq!(last.step, 7);
+        assert_eq!(last.program_counter, 4);
+        assert_eq!(last.instruction, Instruction(Operation::Jmp, -3));
+        assert!(load_machine(PROGRAM).expect("program").trace().is_none());
+    }
+
+    #[test]
+    fn exports() {
+        let mut machine = load_machine("nop +0\nacc -4\njmp -9")
+            .expect("program")
+            .with_trace();
+        machine.run_to_first();
+        let trace = machine.trace().expect("trace");
+
+        let mut csv = vec![];
+        trace.write_csv(&mut csv).expect("csv");
+        assert_eq!(
+            String::from_utf8(csv).expect("utf8"),
+            "step,program_counter,instruction,accumulator_before,accumulator_after\n\
+             1,0,nop +0,0,0\n\
+             2,1,acc -4,0,-4\n\
+             3,2,jmp -9,-4,-4\n"
+        );
+
+        let mut json = vec![];
+        trace.write_json_lines(&mut json).expect("json");
+        let json = String::from_utf8(json).expect("utf8");
+        assert_eq!(json.lines().count(), 3);
+        assert_eq!(
+            json.lines().nth(1),
+            Some(
+                "{\"step\":2,\"program_counter\":1,\"instruction\":\"acc -4\",\"accumulator_before\":0,\"accumulator_after\":-4}"
+            )
+        );
+    }
+
+    #[derive(Debug)]
+    struct Quoted;
+
+    impl Opcode for Quoted {
+        fn name(&self) -> &str {
+            "say\"\\\t"
+        }
+        fn execute(&self, _argument: isize, _state: &mut State) -> Flow {
+            Flow::Next
+        }
+    }
+
+    #[test]
+    fn json_escapes() {
+        let operation = Operation::Custom(Custom(Arc::new(Quoted)));
+        let mut machine = Machine::new(vec![Instruction(operation, 1)]).with_trace();
+        machine.run_to_first();
+        let mut json = vec![];
+        machine
+            .trace()
+            .expect("trace")
+            .write_json_lines(&mut json)
+            .expect("json");
+        assert!(String::from_utf8(json)
+            .expect("utf8")
+            .contains("\"instruction\":\"say\\\"\\\\\\t +1\","));
+    }
+
+    #[test]
+    fn diff_patched_run() {
+        let mut original = load_machine(PROGRAM).expect("program").with_trace();
+        original.run_to_first();
+        let mut patched = load_machine(PROGRAM).expect("program").with_trace();
+        patched.patch(7, Instruction(Operation::Nop, -4));
+        patched.run_to_first();
+
+        let original = original.trace().expect("trace");
+        let patched = patched.trace().expect("trace");
+        assert_eq!(original.first_divergence(patched), Some(4));
+        assert_eq!(
+            patched.entries()[4].instruction,
+            Instruction(Operation::Nop, -4)
+        );
+        assert_eq!(original.first_divergence(original), None);
+    }
+
+    #[test]
+    fn step_back() {
+        let mut machine = load_machine(PROGRAM)
+            .expect("program")
+            .with_history(10)
+            .with_trace();
+        machine.step();
+        machine.step();
+        assert!(machine.step_back());
+        machine.step();
+        machine.step();
+        let trace = machine.trace().expect("trace");
+        let steps: Vec<_> = trace
+            .entries()
+            .iter()
+            .map(|entry| (entry.step, entry.program_counter))
+            .collect();
+        assert_eq!(steps, vec![(1, 0), (2, 1), (3, 2)]);
+
+        let mut straight = load_machine(PROGRAM).expect("program").with_trace();
+        for _ in 0..3 {
+            straight.step();
+        }
+        assert_eq!(
+            straight.trace().expect("trace").first_divergence(trace),
+            None
+        );
+    }
+}